@@ -0,0 +1,58 @@
+use tcod;
+
+use components::Stats;
+use screen::Screen;
+use state_machine::{Event, Layer, State, Transition};
+
+const SHEET_WIDTH: i32 = 30;
+
+/// Read-only screen with the player's stats, closed by any key
+pub struct CharacterSheet {
+    lines: Vec<String>,
+}
+
+impl CharacterSheet {
+    pub fn new(stats: &Stats) -> CharacterSheet {
+        CharacterSheet {
+            lines: vec![
+                "Character information".into(),
+                String::new(),
+                format!("Name: {}", stats.name),
+                format!("Level: {}", stats.level),
+                format!("Experience: {} / {}", stats.xp, stats.xp_to_next_level),
+                format!("Kills: {}", stats.kills),
+                String::new(),
+                format!("Maximum HP: {}", stats.max_hp),
+                format!("Attack: {} ({} base)", stats.attack, stats.base_attack),
+                format!("Defense: {} ({} base)", stats.defense, stats.base_defense),
+            ],
+        }
+    }
+}
+
+impl State for CharacterSheet {
+    fn render(&self, screen: &mut Screen) {
+        let height = self.lines.len() as i32;
+        let x = screen.width() / 2 - SHEET_WIDTH / 2;
+        let y = screen.height() / 2 - height / 2;
+        screen.fill(x, y, SHEET_WIDTH, height, tcod::colors::BLACK);
+        for (i, line) in self.lines.iter().enumerate() {
+            screen.print(x, y + i as i32, line, tcod::colors::WHITE);
+        }
+    }
+
+    fn layer(&self) -> Layer {
+        Layer::Transparent
+    }
+
+    fn update(&mut self) -> Transition {
+        Transition::None
+    }
+
+    fn handle_event(&mut self, event: Event) -> Transition {
+        match event {
+            Event::Key(_) | Event::MouseClick(..) => Transition::Pop,
+            Event::MouseMove(..) => Transition::None,
+        }
+    }
+}
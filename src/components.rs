@@ -2,8 +2,10 @@ use specs;
 use tcod;
 use std;
 use map;
+use monsters;
+use pathfinding;
 
-use specs::VecStorage;
+use specs::{NullStorage, VecStorage};
 use specs::World;
 use specs::{WriteStorage, WriteExpect, ReadStorage, ReadExpect, System};
 use specs::{Dispatcher, DispatcherBuilder};
@@ -11,8 +13,10 @@ use specs::{Dispatcher, DispatcherBuilder};
 use std::sync::{Arc, Mutex};
 
 use observer;
+use screen::{Grid, Screen};
+use serde_color;
 
-#[derive(Component)]
+#[derive(Component, Serialize, Deserialize)]
 #[storage(VecStorage)]
 struct Position {
     x: i32,
@@ -22,7 +26,38 @@ struct Position {
     z: i8
 }
 
-#[derive(Component)]
+/// What happens to an entity when it dies.
+/// Kept as data rather than a closure so it survives a save and load.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub enum DeathCallback {
+    Player,
+    /// the monster leaves a corpse with the given appearance
+    Monster {
+        corpse: char,
+        #[serde(with = "serde_color")]
+        color: tcod::colors::Color,
+    },
+}
+
+impl DeathCallback {
+    fn callback(&self, props: &mut Properties, display: &mut Displayable) {
+        match *self {
+            DeathCallback::Player => {
+                props.alive = false;
+                display.char = '%';
+                display.color = tcod::colors::DARK_RED;
+            }
+            DeathCallback::Monster { corpse, color } => {
+                props.alive = false;
+                props.blocks = false;
+                display.char = corpse;
+                display.color = color;
+            }
+        }
+    }
+}
+
+#[derive(Component, Serialize, Deserialize)]
 #[storage(VecStorage)]
 pub struct Properties {
     name: String,
@@ -30,20 +65,18 @@ pub struct Properties {
     pub alive: bool,
     max_hp: i32,
     hp: i32,
-    death_callback: Option<Box<Mutex<Fn(&mut Properties, &mut Displayable) + std::marker::Send + 'static>>>
+    death_callback: Option<DeathCallback>
 }
 
 impl Properties {
-    fn new<FN>(name: &str, blocks: bool, alive: bool, max_hp: i32, hp: i32, f: FN) -> Self 
-        where FN: Fn(&mut Properties, &mut Displayable) + std::marker::Send + 'static
-    {
+    fn new(name: &str, blocks: bool, alive: bool, max_hp: i32, hp: i32, death_callback: DeathCallback) -> Self {
         Properties {
             name: name.into(),
             blocks: blocks,
             alive: alive,
             max_hp: max_hp,
             hp: hp,
-            death_callback: Some(Box::new(Mutex::new(f)))
+            death_callback: Some(death_callback)
         }
     }
 }
@@ -60,10 +93,20 @@ impl Position {
     }
 }
 
-#[derive(Component)]
+/// An entity left behind on another floor of the dungeon.
+/// Its position is moved in here so none of the systems see it until the player comes back.
+#[derive(Component, Serialize, Deserialize)]
+#[storage(VecStorage)]
+struct Dormant {
+    depth: i32,
+    position: Position,
+}
+
+#[derive(Component, Serialize, Deserialize)]
 #[storage(VecStorage)]
 pub struct Displayable {
     pub char: char,
+    #[serde(with = "serde_color")]
     pub color: tcod::colors::Color,
 }
 
@@ -76,30 +119,563 @@ impl Displayable {
     }
 }
 
+/// Sheds light around the entity, brightest where it stands
+#[derive(Component, Clone, Copy, Debug, Serialize, Deserialize)]
+#[storage(VecStorage)]
+#[serde(deny_unknown_fields)]
+pub struct LightSource {
+    pub radius: i32,
+    #[serde(with = "serde_color")]
+    pub color: tcod::colors::Color,
+    /// how fast the light fades away from the source, 1 fades evenly and more keeps it close
+    pub falloff: f32,
+}
+
+/// What the player carries everywhere
+const TORCH: LightSource = LightSource {
+    radius: 10,
+    color: tcod::colors::Color { r: 255, g: 210, b: 150 },
+    falloff: 1.0,
+};
+
+const BRAZIER: LightSource = LightSource {
+    radius: 6,
+    color: tcod::colors::Color { r: 255, g: 130, b: 40 },
+    falloff: 1.5,
+};
+
 #[derive(Component)]
 #[storage(VecStorage)]
 pub struct MoveEvent(pub i32, pub i32);
 
 #[derive(Component)]
 #[storage(VecStorage)]
-struct MeleeEvent(specs::Entity);
+struct MeleeEvent(Vec<specs::Entity>);
+
+/// What last changed an entity's hp, to know who earns the experience and how it died
+#[derive(Clone, Copy, PartialEq)]
+enum HpCause {
+    Attack(specs::Entity),
+    Poison,
+    Trap,
+    Healing,
+}
+
+/// Change of hp to apply this turn, and what caused it
+#[derive(Component)]
+#[storage(VecStorage)]
+struct HpChange(i32, HpCause);
+
+/// How an entity died, like "Killed by the orc"
+#[derive(Component)]
+#[storage(VecStorage)]
+pub struct Death(pub String);
+
+const LEVEL_UP_BASE: i32 = 200;
+const LEVEL_UP_FACTOR: i32 = 150;
+
+/// Experience needed to go from `level` to the next one
+pub fn xp_to_next_level(level: i32) -> i32 {
+    LEVEL_UP_BASE + level * LEVEL_UP_FACTOR
+}
+
+/// Experience earned by an entity that can level up, the player
+#[derive(Component, Serialize, Deserialize)]
+#[storage(VecStorage)]
+pub struct Experience {
+    pub xp: i32,
+    pub level: i32,
+    pub kills: i32,
+}
+
+/// What the player can improve when leveling up
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LevelUpChoice {
+    Constitution,
+    Strength,
+    Agility,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EffectKind {
+    /// loses `power` hp every turn
+    Poison,
+    /// moves in random directions
+    Confusion,
+    /// does nothing at all
+    Sleep,
+    /// gains `power` hp every turn
+    Regeneration,
+}
+
+impl EffectKind {
+    fn harmful(&self) -> bool {
+        *self != EffectKind::Regeneration
+    }
+
+    fn adjective(&self) -> &'static str {
+        match *self {
+            EffectKind::Poison => "poisoned",
+            EffectKind::Confusion => "confused",
+            EffectKind::Sleep => "asleep",
+            EffectKind::Regeneration => "regenerating",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub struct StatusEffect {
+    pub kind: EffectKind,
+    /// turns left before it wears off
+    pub turns: i32,
+    pub power: i32,
+}
+
+/// Every effect an entity is under, at most one of each kind
+#[derive(Component, Serialize, Deserialize)]
+#[storage(VecStorage)]
+pub struct StatusEffects(pub Vec<StatusEffect>);
+
+/// Effect given to whoever gets hurt by this entity's melee attacks
+#[derive(Component, Serialize, Deserialize)]
+#[storage(VecStorage)]
+pub struct OnHit(pub StatusEffect);
+
+/// "You are" for the player, "<name> is" for everyone else
+fn subject(entity: specs::Entity, player: specs::Entity, name: &str) -> String {
+    if entity == player {
+        "You are".into()
+    } else {
+        format!("{} is", name)
+    }
+}
+
+/// Puts `entity` under `effect`, getting the same effect again only makes it last longer
+fn add_effect(effects: &mut WriteStorage<StatusEffects>, dispatcher: &mut observer::Dispatcher<'static>, player: specs::Entity, entity: specs::Entity, name: &str, effect: StatusEffect) {
+    if effects.get(entity).is_none() {
+        effects.insert(entity, StatusEffects(Vec::new())).unwrap();
+    }
+    let current = &mut effects.get_mut(entity).unwrap().0;
+    if let Some(existing) = current.iter_mut().find(|e| e.kind == effect.kind) {
+        existing.turns = std::cmp::max(existing.turns, effect.turns);
+        existing.power = std::cmp::max(existing.power, effect.power);
+        return;
+    }
+    current.push(effect);
+    let bad_news = (entity == player) == effect.kind.harmful();
+    let color = if bad_news { tcod::colors::LIGHT_RED } else { tcod::colors::LIGHT_GREEN };
+    dispatcher.dispatch(observer::Event::Log(entity, format!("{} {}!", subject(entity, player, name), effect.kind.adjective()), color));
+}
+
+const HEAL_AMOUNT: i32 = 4;
+/// How far the scrolls reach for a monster
+const SCROLL_RANGE: i32 = 8;
+const INVENTORY_SIZE: usize = 26;
+
+/// Where an item is worn or wielded, an entity has one of each
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub enum Slot {
+    Weapon,
+    Armor,
+    Shield,
+    Ring,
+}
+
+pub const SLOTS: [Slot; 4] = [Slot::Weapon, Slot::Armor, Slot::Shield, Slot::Ring];
+
+impl Slot {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Slot::Weapon => "weapon",
+            Slot::Armor => "armor",
+            Slot::Shield => "shield",
+            Slot::Ring => "ring",
+        }
+    }
+}
+
+/// What an item gives to whoever has it equipped
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Bonus {
+    pub slot: Slot,
+    pub attack: i32,
+    pub defense: i32,
+}
+
+impl Bonus {
+    /// e.g. "+2 attack, +1 defense"
+    pub fn description(&self) -> String {
+        let mut parts = Vec::new();
+        if self.attack != 0 {
+            parts.push(format!("{:+} attack", self.attack));
+        }
+        if self.defense != 0 {
+            parts.push(format!("{:+} defense", self.defense));
+        }
+        parts.join(", ")
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub enum ItemKind {
+    HealingPotion,
+    Dagger,
+    Sword,
+    LeatherArmor,
+    Shield,
+    RingOfProtection,
+    Shortbow,
+    PotionOfRegeneration,
+    ScrollOfConfusion,
+    ScrollOfSleep,
+}
+
+impl ItemKind {
+    fn name(&self) -> &'static str {
+        match *self {
+            ItemKind::HealingPotion => "healing potion",
+            ItemKind::Dagger => "dagger",
+            ItemKind::Sword => "sword",
+            ItemKind::LeatherArmor => "leather armor",
+            ItemKind::Shield => "shield",
+            ItemKind::RingOfProtection => "ring of protection",
+            ItemKind::Shortbow => "shortbow",
+            ItemKind::PotionOfRegeneration => "potion of regeneration",
+            ItemKind::ScrollOfConfusion => "scroll of confusion",
+            ItemKind::ScrollOfSleep => "scroll of sleep",
+        }
+    }
+
+    fn appearance(&self) -> (char, tcod::colors::Color) {
+        match *self {
+            ItemKind::HealingPotion => ('!', tcod::colors::VIOLET),
+            ItemKind::Dagger => ('-', tcod::colors::SKY),
+            ItemKind::Sword => ('/', tcod::colors::SKY),
+            ItemKind::LeatherArmor => ('[', tcod::colors::DARKER_ORANGE),
+            ItemKind::Shield => ('[', tcod::colors::DARKER_ORANGE),
+            ItemKind::RingOfProtection => ('=', tcod::colors::GOLD),
+            ItemKind::Shortbow => (')', tcod::colors::SEPIA),
+            ItemKind::PotionOfRegeneration => ('!', tcod::colors::LIGHT_GREEN),
+            ItemKind::ScrollOfConfusion => ('#', tcod::colors::LIGHT_YELLOW),
+            ItemKind::ScrollOfSleep => ('#', tcod::colors::LIGHT_BLUE),
+        }
+    }
+
+    /// The slot and bonuses of the items that can be equipped
+    pub fn bonus(&self) -> Option<Bonus> {
+        let (slot, attack, defense) = match *self {
+            ItemKind::HealingPotion
+            | ItemKind::PotionOfRegeneration
+            | ItemKind::ScrollOfConfusion
+            | ItemKind::ScrollOfSleep => return None,
+            ItemKind::Dagger => (Slot::Weapon, 2, 0),
+            ItemKind::Sword => (Slot::Weapon, 3, 0),
+            ItemKind::LeatherArmor => (Slot::Armor, 0, 1),
+            ItemKind::Shield => (Slot::Shield, 0, 1),
+            ItemKind::RingOfProtection => (Slot::Ring, 0, 1),
+            ItemKind::Shortbow => (Slot::Weapon, 0, 0),
+        };
+        Some(Bonus {
+            slot: slot,
+            attack: attack,
+            defense: defense,
+        })
+    }
+
+    /// Range and power of the weapons that shoot
+    pub fn ranged(&self) -> Option<(i32, i32)> {
+        match *self {
+            ItemKind::Shortbow => Some((8, 6)),
+            _ => None,
+        }
+    }
+
+    /// The effect the item gives when used, and whether it goes to the user rather than the closest
+    /// visible monster
+    fn use_effect(&self) -> Option<(StatusEffect, bool)> {
+        let (kind, turns, power, on_self) = match *self {
+            ItemKind::PotionOfRegeneration => (EffectKind::Regeneration, 10, 1, true),
+            ItemKind::ScrollOfConfusion => (EffectKind::Confusion, 10, 0, false),
+            ItemKind::ScrollOfSleep => (EffectKind::Sleep, 8, 0, false),
+            _ => return None,
+        };
+        Some((StatusEffect { kind: kind, turns: turns, power: power }, on_self))
+    }
+
+    /// Power of the item when thrown at someone
+    fn throw_power(&self) -> i32 {
+        match *self {
+            ItemKind::HealingPotion => 0,
+            ItemKind::Dagger => 4,
+            ItemKind::Sword => 3,
+            _ => 1,
+        }
+    }
+}
+
+/// How far items can be thrown
+pub const THROW_RANGE: i32 = 6;
+
+#[derive(Component, Serialize, Deserialize)]
+#[storage(VecStorage)]
+pub struct Item(pub ItemKind);
+
+/// Items carried by an entity, they have no `Position` while in there
+#[derive(Component)]
+#[storage(VecStorage)]
+pub struct Inventory(pub Vec<specs::Entity>);
+
+#[derive(Component)]
+#[storage(VecStorage)]
+pub struct PickUpEvent;
 
+/// Closes the open door at the given tile, next to the entity
 #[derive(Component)]
 #[storage(VecStorage)]
-struct HpChange(i32);
+pub struct CloseDoorEvent(pub i32, pub i32);
 
 #[derive(Component)]
 #[storage(VecStorage)]
+pub struct DropEvent(pub specs::Entity);
+
+#[derive(Component)]
+#[storage(VecStorage)]
+pub struct UseItemEvent(pub specs::Entity);
+
+/// Marks a carried item as worn or wielded in the given slot
+#[derive(Component, Serialize, Deserialize)]
+#[storage(VecStorage)]
+pub struct Equipped(pub Slot);
+
+/// Equips the item, or takes it off if it already is
+#[derive(Component)]
+#[storage(VecStorage)]
+pub struct EquipEvent(pub specs::Entity);
+
+/// Shoots the equipped ranged weapon at a tile
+#[derive(Component)]
+#[storage(VecStorage)]
+pub struct ShootEvent(pub i32, pub i32);
+
+/// Throws a carried item at a tile
+#[derive(Component)]
+#[storage(VecStorage)]
+pub struct ThrowEvent(pub specs::Entity, pub i32, pub i32);
+
+/// A projectile that reached an entity: who sent it, how hard it hits and what it was
+struct RangedHit {
+    attacker: specs::Entity,
+    power: i32,
+    weapon: String,
+}
+
+#[derive(Component)]
+#[storage(VecStorage)]
+struct RangedEvent(Vec<RangedHit>);
+
+/// Follows a projectile from `from` towards `to`, it stops at the first blocking entity or before
+/// the first wall. Returns the last tile it flew over and the entity it hit, if any.
+fn trace_shot(map: &map::Map, blockers: &[(specs::Entity, i32, i32)], from: (i32, i32), to: (i32, i32)) -> ((i32, i32), Option<specs::Entity>) {
+    let mut reached = from;
+    for (x, y) in map::line(from, to) {
+        if !map.can_walk(x, y) {
+            break;
+        }
+        reached = (x, y);
+        if let Some(&(e, _, _)) = blockers.iter().find(|&&(_, bx, by)| (bx, by) == (x, y)) {
+            return (reached, Some(e));
+        }
+    }
+    (reached, None)
+}
+
+fn add_ranged_hit(ranged: &mut WriteStorage<RangedEvent>, target: specs::Entity, hit: RangedHit) {
+    if ranged.get(target).is_none() {
+        ranged.insert(target, RangedEvent(Vec::new())).unwrap();
+    }
+    ranged.get_mut(target).unwrap().0.push(hit);
+}
+
+#[derive(Component, Serialize, Deserialize)]
+#[storage(VecStorage)]
 pub struct Fighter {
     defense: i32,
-    attack: i32
+    attack: i32,
+    /// experience given to whoever kills this fighter
+    xp: i32
 }
 
 impl Fighter {
-    pub fn new(attack: i32, defense: i32) -> Self {
+    pub fn new(attack: i32, defense: i32, xp: i32) -> Self {
         Fighter {
             attack: attack,
-            defense: defense
+            defense: defense,
+            xp: xp
+        }
+    }
+}
+
+/// Attack and defense of a fighter once the bonuses of its equipment are added
+fn effective_stats<'a>(
+    entity: specs::Entity,
+    fighters: &ReadStorage<'a, Fighter>,
+    inventories: &ReadStorage<'a, Inventory>,
+    items: &ReadStorage<'a, Item>,
+    equipped: &ReadStorage<'a, Equipped>,
+) -> Option<(i32, i32)> {
+    let fighter = fighters.get(entity)?;
+    let (mut attack, mut defense) = (fighter.attack, fighter.defense);
+    if let Some(inventory) = inventories.get(entity) {
+        for item in inventory.0.iter().filter(|i| equipped.get(**i).is_some()) {
+            if let Some(bonus) = items.get(*item).and_then(|i| i.0.bonus()) {
+                attack += bonus.attack;
+                defense += bonus.defense;
+            }
+        }
+    }
+    Some((attack, defense))
+}
+
+/// The equipped weapon of `entity` that can shoot
+fn ranged_weapon<'a>(
+    entity: specs::Entity,
+    inventories: &ReadStorage<'a, Inventory>,
+    items: &ReadStorage<'a, Item>,
+    equipped: &ReadStorage<'a, Equipped>,
+) -> Option<ItemKind> {
+    inventories.get(entity)?.0.iter()
+        .filter(|i| equipped.get(**i).is_some())
+        .filter_map(|i| items.get(*i).map(|item| item.0))
+        .find(|kind| kind.ranged().is_some())
+}
+
+#[derive(Component, Default)]
+#[storage(NullStorage)]
+pub struct Ai;
+
+/// Energy an actor needs to take an action
+pub const ENERGY_THRESHOLD: i32 = 100;
+/// Speed of the player and of most monsters, they act once every 10 ticks
+pub const NORMAL_SPEED: i32 = 10;
+
+/// Actors gain `speed` energy every tick of game time and act once they reach `ENERGY_THRESHOLD`
+#[derive(Component, Serialize, Deserialize)]
+#[storage(VecStorage)]
+pub struct Energy {
+    pub speed: i32,
+    pub energy: i32,
+}
+
+/// Marks the actors whose turn it is during the current tick
+#[derive(Component, Default)]
+#[storage(NullStorage)]
+struct Acting;
+
+/// Picks the actors with enough energy to act this tick, and makes them pay for it
+struct Schedule;
+impl<'a> System<'a> for Schedule {
+    type SystemData = (specs::Entities<'a>, ReadStorage<'a, Position>, ReadStorage<'a, Properties>, WriteStorage<'a, Energy>, WriteStorage<'a, Acting>);
+
+    fn run(&mut self, (entities, position, properties, mut energy, mut acting): Self::SystemData) {
+        use specs::Join;
+
+        for (ent, _, prop, energy) in (&*entities, &position, &properties, &mut energy).join() {
+            if prop.alive && energy.energy >= ENERGY_THRESHOLD {
+                energy.energy -= ENERGY_THRESHOLD;
+                acting.insert(ent, Acting).unwrap();
+            }
+        }
+    }
+}
+
+/// Ends the tick: everyone on the floor gains energy and the turns are over
+struct PassTime;
+impl<'a> System<'a> for PassTime {
+    type SystemData = (specs::Entities<'a>, ReadStorage<'a, Position>, WriteStorage<'a, Energy>, WriteStorage<'a, Acting>);
+
+    fn run(&mut self, (entities, position, mut energy, mut acting): Self::SystemData) {
+        use specs::Join;
+
+        for (_, energy) in (&position, &mut energy).join() {
+            energy.energy += energy.speed;
+        }
+        let done: Vec<specs::Entity> = (&*entities, &acting).join().map(|(e, _)| e).collect();
+        for e in done {
+            acting.remove(e);
+        }
+    }
+}
+
+/// Returns the single step (dx, dy) that brings (x, y) closer to (target_x, target_y)
+fn step_towards(x: i32, y: i32, target_x: i32, target_y: i32) -> (i32, i32) {
+    let dx = target_x - x;
+    let dy = target_y - y;
+    let distance = ((dx.pow(2) + dy.pow(2)) as f32).sqrt();
+    if distance == 0.0 {
+        return (0, 0);
+    }
+    ((dx as f32 / distance).round() as i32, (dy as f32 / distance).round() as i32)
+}
+
+/// Monsters run away once their hp falls to this fraction of their max, 4 is a quarter
+const FLEE_HP_FRACTION: i32 = 4;
+/// What stepping on a known trap is worth to a monster, in tiles of detour
+const TRAP_PATH_COST: i32 = 10;
+
+struct MonsterAi;
+impl<'a> System<'a> for MonsterAi {
+    type SystemData = (specs::Entities<'a>, ReadExpect<'a, map::Map>, ReadExpect<'a, Player>, ReadStorage<'a, Ai>, ReadStorage<'a, Position>, ReadStorage<'a, Properties>, WriteStorage<'a, MoveEvent>, ReadStorage<'a, Acting>);
+
+    fn run(&mut self, (entities, map, player, ai, position, properties, mut event_storage, acting): Self::SystemData) {
+        use specs::Join;
+
+        let (player_x, player_y) = match (position.get(player.0), properties.get(player.0)) {
+            (Some(pos), Some(prop)) if prop.alive => (pos.x, pos.y),
+            _ => return, // nothing to hunt
+        };
+
+        let blockers: Vec<(i32, i32)> = (&position, &properties).join()
+            .filter(|(_, prop)| prop.blocks)
+            .map(|(pos, _)| (pos.x, pos.y))
+            .collect();
+
+        // monsters steer clear of the traps they have seen go off
+        let traps: Vec<(i32, i32)> = (0..map.height())
+            .flat_map(|y| (0..map.width()).map(move |x| (x, y)))
+            .filter(|&(x, y)| map.tile_at(x, y) == map::TileKind::Trap { revealed: true })
+            .collect();
+
+        for (ent, _, pos, prop, _) in (&*entities, &ai, &position, &properties, &acting).join() {
+            // a monster only notices the player if the player can see it
+            if !prop.alive || !map.is_in_fov(pos.x, pos.y) {
+                continue;
+            }
+            let mut finder = pathfinding::Pathfinder::new(&*map)
+                .obstacles(blockers.iter().cloned().filter(|&b| b != (pos.x, pos.y)));
+            for &(x, y) in traps.iter() {
+                finder = finder.cost(x, y, TRAP_PATH_COST);
+            }
+
+            let escape = if prop.hp * FLEE_HP_FRACTION <= prop.max_hp {
+                let chase = finder.dijkstra(&[(player_x, player_y)]);
+                finder.flee(&chase).downhill(pos.x, pos.y)
+            } else {
+                None
+            };
+            let (dx, dy) = match escape {
+                Some((x, y)) => (x - pos.x, y - pos.y),
+                // healthy or cornered, it goes for the player
+                None => match finder.astar((pos.x, pos.y), (player_x, player_y)) {
+                    Some(ref path) if !path.is_empty() => (path[0].0 - pos.x, path[0].1 - pos.y),
+                    // no way around the other monsters, just push towards the player
+                    _ => step_towards(pos.x, pos.y, player_x, player_y),
+                },
+            };
+            if dx != 0 || dy != 0 {
+                // bumping into the player is turned into a melee attack by HandleMoveEvents
+                event_storage.insert(ent, MoveEvent(dx, dy)).unwrap();
+            }
         }
     }
 }
@@ -113,7 +689,6 @@ impl<'a> System<'a> for Print {
 
     fn run(&mut self, (mut console, map, position, displayable): Self::SystemData) {
         use specs::Join;
-        use tcod::Console;
 
         let mut con = console.get_mut();
 
@@ -121,15 +696,14 @@ impl<'a> System<'a> for Print {
         data.sort_by(|&a, &b| a.0.z.cmp(&b.0.z));
 
         for (position, _) in data.iter() {
-            (*con).put_char(position.old_x, position.old_y, ' ', tcod::BackgroundFlag::None);
+            con.set_glyph(position.old_x, position.old_y, ' ');
         }
 
         map.render(&mut *con);
 
         for (position, displayable) in data {
             if map.is_in_fov(position.x, position.y) {
-                (*con).set_default_foreground(displayable.color);
-                (*con).put_char(position.x, position.y, displayable.char, tcod::BackgroundFlag::None);
+                con.put_char(position.x, position.y, displayable.char, displayable.color);
             }
         }
     }
@@ -137,80 +711,405 @@ impl<'a> System<'a> for Print {
 
 struct HandleHpChange;
 impl <'a> System<'a> for HandleHpChange {
-    type SystemData = (specs::Entities<'a>, WriteStorage<'a, HpChange>, WriteStorage<'a, Properties>, WriteExpect<'a, observer::Dispatcher<'static>>, WriteStorage<'a, Displayable>);
+    type SystemData = (specs::Entities<'a>, WriteStorage<'a, HpChange>, WriteStorage<'a, Properties>, WriteExpect<'a, observer::Dispatcher<'static>>, WriteStorage<'a, Displayable>, ReadExpect<'a, Player>, ReadStorage<'a, Fighter>, WriteStorage<'a, Experience>, WriteStorage<'a, Death>, WriteStorage<'a, LightSource>);
 
-    fn run(&mut self, (entities, mut hp_change, mut properties, mut dispatcher, mut displayable): Self::SystemData) {
+    fn run(&mut self, (entities, mut hp_change, mut properties, mut dispatcher, mut displayable, player, fighter, mut experience, mut deaths, mut lights): Self::SystemData) {
         use specs::Join;
 
         let mut to_remove = Vec::new();
+        let mut died = Vec::new();
 
         for (ent, hp, prop, disp) in (&*entities, &mut hp_change, &mut properties, &mut displayable).join() {
-            prop.hp += hp.0;
+            to_remove.push(ent);
+            if !prop.alive {
+                continue; // can't hurt or heal a corpse
+            }
+            prop.hp = std::cmp::min(prop.hp + hp.0, prop.max_hp);
                 if prop.hp <= 0 {
                     prop.hp = 0;
                     prop.alive = false;
-                    dispatcher.dispatch(observer::Event::Log(ent, format!("{} died", prop.name)));
+                    let color = if ent == player.0 { tcod::colors::RED } else { tcod::colors::ORANGE };
+                    dispatcher.dispatch(observer::Event::Log(ent, format!("{} died", prop.name), color));
                     if let Some(callback) = prop.death_callback.take() {
-                        (callback.lock().unwrap())(prop, disp);
+                        callback.callback(prop, disp);
+                    }
+                    died.push((ent, hp.1));
+                    // the killer earns the experience the victim was worth
+                    if let HpCause::Attack(killer) = hp.1 {
+                        let worth = fighter.get(ent).map_or(0, |f| f.xp);
+                        if let Some(exp) = experience.get_mut(killer) {
+                            exp.xp += worth;
+                            exp.kills += 1;
+                            dispatcher.dispatch(observer::Event::Log(killer, format!("You gain {} experience points.", worth), tcod::colors::LIGHT_YELLOW));
+                        }
                     }
                 }
-            to_remove.push(ent);
         }
 
         for e in to_remove {
             hp_change.remove(e);
         }
+
+        for (ent, cause) in died {
+            let cause = match cause {
+                HpCause::Attack(killer) => {
+                    let name = properties.get(killer).map_or(String::new(), |p| p.name.to_lowercase());
+                    format!("Killed by the {}", name)
+                }
+                HpCause::Poison => "Died of poison".into(),
+                HpCause::Trap => "Killed by a trap".into(),
+                HpCause::Healing => "Died".into(),
+            };
+            deaths.insert(ent, Death(cause)).unwrap();
+            // a glowing monster goes dark, the player's torch stays lit over the final map
+            if ent != player.0 {
+                lights.remove(ent);
+            }
+        }
     }
 }
 
 struct HandleMelee;
 impl<'a> System<'a> for HandleMelee {
-    type SystemData = (specs::Entities<'a>, WriteExpect<'a, observer::Dispatcher<'static>>, WriteStorage<'a, MeleeEvent>, ReadStorage<'a, Properties>, ReadStorage<'a, Fighter>, WriteStorage<'a, HpChange>);
+    type SystemData = (specs::Entities<'a>, WriteExpect<'a, observer::Dispatcher<'static>>, WriteStorage<'a, MeleeEvent>, ReadStorage<'a, Properties>, ReadStorage<'a, Fighter>, WriteStorage<'a, HpChange>, ReadExpect<'a, Player>, ReadStorage<'a, Inventory>, ReadStorage<'a, Item>, ReadStorage<'a, Equipped>, ReadStorage<'a, OnHit>, WriteStorage<'a, StatusEffects>);
 
-    fn run(&mut self, (entities, mut dispatcher, mut melee_storage, properties, fighter_storage, mut hp_change): Self::SystemData) {
+    fn run(&mut self, (entities, mut dispatcher, mut melee_storage, properties, fighter_storage, mut hp_change, player, inventories, items, equipped, on_hit, mut effects): Self::SystemData) {
         use specs::Join;
 
         let mut to_remove = Vec::new();
 
         for (ent, melee, prop) in (&*entities, &mut melee_storage, &properties).join() {
-            if let Some(ref atk) = fighter_storage.get(melee.0) {
-                // if the one attacking cannot attack, we don't attack
-                let def = if let Some(ref def) = fighter_storage.get(ent) {
-                    def.defense
+            for attacker in melee.0.iter() {
+                if let Some((attack, _)) = effective_stats(*attacker, &fighter_storage, &inventories, &items, &equipped) {
+                    // if the one attacking cannot attack, we don't attack
+                    let def = if let Some((_, defense)) = effective_stats(ent, &fighter_storage, &inventories, &items, &equipped) {
+                        defense
+                    } else {
+                        0 // if it's not a fighter, then it doesn't have any defense!
+                    };
+                    let p = properties.get(*attacker).unwrap();
+                    let mut damage = attack - def;
+                    // the player being hurt is what the message log should make stand out
+                    let color = if ent == player.0 { tcod::colors::LIGHT_RED } else { tcod::colors::WHITE };
+                    if damage > 0 {
+                        dispatcher.dispatch(observer::Event::Log(ent, format!("{} attacked the {} for {} damage", p.name, prop.name, damage), color));
+                        if let Some(change) = hp_change.get(ent) {
+                            damage -= change.0;
+                        }
+                        hp_change.insert(ent, HpChange(-damage, HpCause::Attack(*attacker))).unwrap();
+                        if let Some(hit) = on_hit.get(*attacker) {
+                            add_effect(&mut effects, &mut *dispatcher, player.0, ent, &prop.name, hit.0);
+                        }
+                    } else {
+                        dispatcher.dispatch(observer::Event::Log(ent, format!("{} attacked the {} but there was no effect!", p.name, prop.name), tcod::colors::GREY));
+                    }
+                }
+            }
+            to_remove.push(ent);
+        }
+        for e in to_remove {
+            melee_storage.remove(e);
+        }
+    }
+}
+
+struct HandleInventory;
+impl<'a> System<'a> for HandleInventory {
+    type SystemData = (specs::Entities<'a>, WriteExpect<'a, observer::Dispatcher<'static>>, WriteStorage<'a, Position>, ReadStorage<'a, Properties>, ReadStorage<'a, Item>, WriteStorage<'a, Inventory>, WriteStorage<'a, PickUpEvent>, WriteStorage<'a, DropEvent>, WriteStorage<'a, UseItemEvent>, WriteStorage<'a, HpChange>, WriteStorage<'a, EquipEvent>, WriteStorage<'a, Equipped>, ReadExpect<'a, map::Map>, WriteStorage<'a, ThrowEvent>, WriteStorage<'a, RangedEvent>, WriteStorage<'a, StatusEffects>, ReadExpect<'a, Player>);
+
+    fn run(&mut self, (entities, mut dispatcher, mut position, properties, items, mut inventories, mut pick_up, mut drop, mut use_item, mut hp_change, mut equip, mut equipped, map, mut throw, mut ranged, mut effects, player): Self::SystemData) {
+        use specs::Join;
+
+        let floor_items: Vec<(specs::Entity, i32, i32)> = (&*entities, &position, &items).join()
+            .map(|(e, pos, _)| (e, pos.x, pos.y))
+            .collect();
+
+        let picking: Vec<specs::Entity> = (&*entities, &pick_up).join().map(|(e, _)| e).collect();
+        for ent in picking {
+            pick_up.remove(ent);
+            let (x, y) = match position.get(ent) {
+                Some(pos) => (pos.x, pos.y),
+                None => continue,
+            };
+            let item = match floor_items.iter().find(|(_, ix, iy)| (*ix, *iy) == (x, y)) {
+                Some(&(item, _, _)) => item,
+                None => continue,
+            };
+            if let Some(inventory) = inventories.get_mut(ent) {
+                let name = &properties.get(item).unwrap().name;
+                if inventory.0.len() >= INVENTORY_SIZE {
+                    dispatcher.dispatch(observer::Event::Log(ent, format!("Your inventory is full, cannot pick up the {}", name), tcod::colors::RED));
                 } else {
-                    0 // if it's not a fighter, then it doesn't have any defense!
-                };
-                let p = properties.get(melee.0).unwrap();
-                let mut damage = atk.attack - def;
+                    dispatcher.dispatch(observer::Event::Log(ent, format!("You picked up a {}!", name), tcod::colors::GREEN));
+                    inventory.0.push(item);
+                    position.remove(item);
+                }
+            }
+        }
+
+        let dropping: Vec<(specs::Entity, specs::Entity)> = (&*entities, &drop).join().map(|(e, d)| (e, d.0)).collect();
+        for (ent, item) in dropping {
+            drop.remove(ent);
+            let (x, y) = match position.get(ent) {
+                Some(pos) => (pos.x, pos.y),
+                None => continue,
+            };
+            if let Some(inventory) = inventories.get_mut(ent) {
+                inventory.0.retain(|i| *i != item);
+                equipped.remove(item);
+                position.insert(item, Position::new(x, y, 0)).unwrap();
+                dispatcher.dispatch(observer::Event::Log(ent, format!("You dropped a {}.", properties.get(item).unwrap().name), tcod::colors::YELLOW));
+            }
+        }
+
+        let mut equipping: Vec<(specs::Entity, specs::Entity)> = (&*entities, &equip).join().map(|(e, u)| (e, u.0)).collect();
+        for &(ent, _) in equipping.iter() {
+            equip.remove(ent);
+        }
+
+        let using: Vec<(specs::Entity, specs::Entity)> = (&*entities, &use_item).join().map(|(e, u)| (e, u.0)).collect();
+        for (ent, item) in using {
+            use_item.remove(ent);
+            let used = match items.get(item).map(|i| i.0) {
+                Some(ItemKind::HealingPotion) => {
+                    let prop = properties.get(ent).unwrap();
+                    if prop.hp >= prop.max_hp {
+                        dispatcher.dispatch(observer::Event::Log(ent, "You are already at full health.".into(), tcod::colors::RED));
+                        false
+                    } else {
+                        dispatcher.dispatch(observer::Event::Log(ent, "Your wounds start to feel better!".into(), tcod::colors::LIGHT_VIOLET));
+                        let change = hp_change.get(ent).map_or(0, |c| c.0);
+                        hp_change.insert(ent, HpChange(change + HEAL_AMOUNT, HpCause::Healing)).unwrap();
+                        true
+                    }
+                }
+                Some(kind) if kind.use_effect().is_some() => {
+                    let (effect, on_self) = kind.use_effect().unwrap();
+                    let target = if on_self {
+                        Some(ent)
+                    } else {
+                        let (x, y) = match position.get(ent) {
+                            Some(pos) => (pos.x, pos.y),
+                            None => continue,
+                        };
+                        (&*entities, &position, &properties).join()
+                            .filter(|&(e, pos, prop)| e != ent && prop.alive && map.is_in_fov(pos.x, pos.y))
+                            .map(|(e, pos, _)| (e, (pos.x - x).pow(2) + (pos.y - y).pow(2)))
+                            .filter(|&(_, distance)| distance <= SCROLL_RANGE.pow(2))
+                            .min_by_key(|&(_, distance)| distance)
+                            .map(|(e, _)| e)
+                    };
+                    match target {
+                        Some(target) => {
+                            let name = properties.get(target).unwrap().name.clone();
+                            add_effect(&mut effects, &mut *dispatcher, player.0, target, &name, effect);
+                            true
+                        }
+                        None => {
+                            dispatcher.dispatch(observer::Event::Log(ent, "No enemy is close enough.".into(), tcod::colors::RED));
+                            false
+                        }
+                    }
+                }
+                // using something that can be worn means putting it on or taking it off
+                Some(_) => {
+                    equipping.push((ent, item));
+                    false
+                }
+                None => false,
+            };
+            if used {
+                if let Some(inventory) = inventories.get_mut(ent) {
+                    inventory.0.retain(|i| *i != item);
+                }
+                entities.delete(item).unwrap();
+            }
+        }
+
+        for (ent, item) in equipping {
+            let bonus = match items.get(item).and_then(|i| i.0.bonus()) {
+                Some(bonus) => bonus,
+                None => continue,
+            };
+            let carried = match inventories.get(ent) {
+                Some(inventory) if inventory.0.contains(&item) => inventory.0.clone(),
+                _ => continue, // only carried items can be equipped
+            };
+            let name = &properties.get(item).unwrap().name;
+            if equipped.remove(item).is_some() {
+                dispatcher.dispatch(observer::Event::Log(ent, format!("You took off the {}.", name), tcod::colors::LIGHT_YELLOW));
+                continue;
+            }
+            // whatever was in that slot comes off first
+            for other in carried {
+                if equipped.get(other).map_or(false, |e| e.0 == bonus.slot) {
+                    equipped.remove(other);
+                    dispatcher.dispatch(observer::Event::Log(ent, format!("You took off the {}.", properties.get(other).unwrap().name), tcod::colors::LIGHT_YELLOW));
+                }
+            }
+            equipped.insert(item, Equipped(bonus.slot)).unwrap();
+            dispatcher.dispatch(observer::Event::Log(ent, format!("You equipped the {} ({}).", name, bonus.description()), tcod::colors::LIGHT_GREEN));
+        }
+
+        let throwing: Vec<(specs::Entity, specs::Entity, i32, i32)> = (&*entities, &throw).join().map(|(e, t)| (e, t.0, t.1, t.2)).collect();
+        for (ent, item, x, y) in throwing {
+            throw.remove(ent);
+            let from = match position.get(ent) {
+                Some(pos) => (pos.x, pos.y),
+                None => continue,
+            };
+            let kind = match items.get(item) {
+                Some(i) => i.0,
+                None => continue,
+            };
+            if !inventories.get(ent).map_or(false, |inventory| inventory.0.contains(&item)) {
+                continue; // can only throw what is carried
+            }
+            inventories.get_mut(ent).unwrap().0.retain(|i| *i != item);
+            equipped.remove(item);
+            let blockers: Vec<(specs::Entity, i32, i32)> = (&*entities, &position, &properties).join()
+                .filter(|&(_, _, prop)| prop.blocks)
+                .map(|(e, pos, _)| (e, pos.x, pos.y))
+                .collect();
+            let ((land_x, land_y), hit) = trace_shot(&map, &blockers, from, (x, y));
+            position.insert(item, Position::new(land_x, land_y, 0)).unwrap();
+            let name = properties.get(item).unwrap().name.clone();
+            match hit {
+                Some(target) => add_ranged_hit(&mut ranged, target, RangedHit { attacker: ent, power: kind.throw_power(), weapon: name }),
+                None => dispatcher.dispatch(observer::Event::Log(ent, format!("The {} lands on the floor.", name), tcod::colors::LIGHT_GREY)),
+            }
+        }
+    }
+}
+
+/// Fires the ranged weapons, then hurts whoever got hit by a projectile this turn
+struct HandleRanged;
+impl<'a> System<'a> for HandleRanged {
+    type SystemData = (specs::Entities<'a>, WriteExpect<'a, observer::Dispatcher<'static>>, ReadExpect<'a, map::Map>, ReadExpect<'a, Player>, ReadStorage<'a, Position>, ReadStorage<'a, Properties>, ReadStorage<'a, Fighter>, ReadStorage<'a, Inventory>, ReadStorage<'a, Item>, ReadStorage<'a, Equipped>, WriteStorage<'a, ShootEvent>, WriteStorage<'a, RangedEvent>, WriteStorage<'a, HpChange>);
+
+    fn run(&mut self, (entities, mut dispatcher, map, player, position, properties, fighters, inventories, items, equipped, mut shoot, mut ranged, mut hp_change): Self::SystemData) {
+        use specs::Join;
+
+        let blockers: Vec<(specs::Entity, i32, i32)> = (&*entities, &position, &properties).join()
+            .filter(|&(_, _, prop)| prop.blocks)
+            .map(|(e, pos, _)| (e, pos.x, pos.y))
+            .collect();
+
+        let shooting: Vec<(specs::Entity, i32, i32)> = (&*entities, &shoot).join().map(|(e, s)| (e, s.0, s.1)).collect();
+        for (ent, x, y) in shooting {
+            shoot.remove(ent);
+            let (from, weapon) = match (position.get(ent), ranged_weapon(ent, &inventories, &items, &equipped)) {
+                (Some(pos), Some(weapon)) => ((pos.x, pos.y), weapon),
+                _ => continue,
+            };
+            let (range, power) = weapon.ranged().unwrap();
+            let target = if (x - from.0).pow(2) + (y - from.1).pow(2) <= range.pow(2) {
+                (x, y)
+            } else {
+                continue; // out of range, the game should not have allowed it
+            };
+            match trace_shot(&map, &blockers, from, target) {
+                (_, Some(hit)) => add_ranged_hit(&mut ranged, hit, RangedHit { attacker: ent, power: power, weapon: weapon.name().into() }),
+                (_, None) => dispatcher.dispatch(observer::Event::Log(ent, "The arrow hits nothing.".into(), tcod::colors::LIGHT_GREY)),
+            }
+        }
+
+        let mut to_remove = Vec::new();
+        for (ent, event, prop) in (&*entities, &ranged, &properties).join() {
+            to_remove.push(ent);
+            let defense = effective_stats(ent, &fighters, &inventories, &items, &equipped).map_or(0, |(_, defense)| defense);
+            let color = if ent == player.0 { tcod::colors::LIGHT_RED } else { tcod::colors::WHITE };
+            for hit in event.0.iter() {
+                let attacker = &properties.get(hit.attacker).unwrap().name;
+                let mut damage = hit.power - defense;
                 if damage > 0 {
-                    dispatcher.dispatch(observer::Event::Log(ent, format!("{} attacked the {} for {} damage", p.name, prop.name, damage)));
+                    dispatcher.dispatch(observer::Event::Log(ent, format!("{} hit the {} with the {} for {} damage", attacker, prop.name, hit.weapon, damage), color));
                     if let Some(change) = hp_change.get(ent) {
                         damage -= change.0;
                     }
-                    hp_change.insert(ent, HpChange(-damage)).unwrap();
+                    hp_change.insert(ent, HpChange(-damage, HpCause::Attack(hit.attacker))).unwrap();
                 } else {
-                    dispatcher.dispatch(observer::Event::Log(ent, format!("{} attacked the {} but there was no effect!", p.name, prop.name)));
+                    dispatcher.dispatch(observer::Event::Log(ent, format!("{} hit the {} with the {} but there was no effect!", attacker, prop.name, hit.weapon), tcod::colors::GREY));
                 }
             }
-            to_remove.push(ent);
         }
         for e in to_remove {
-            melee_storage.remove(e);
+            ranged.remove(e);
+        }
+    }
+}
+
+/// Applies the status effects once per turn and wears them off
+struct HandleStatusEffects;
+impl<'a> System<'a> for HandleStatusEffects {
+    type SystemData = (specs::Entities<'a>, WriteExpect<'a, observer::Dispatcher<'static>>, ReadExpect<'a, Player>, ReadExpect<'a, Rng>, ReadStorage<'a, Properties>, WriteStorage<'a, StatusEffects>, WriteStorage<'a, HpChange>, WriteStorage<'a, MoveEvent>, WriteStorage<'a, ShootEvent>, WriteStorage<'a, ThrowEvent>, WriteStorage<'a, PickUpEvent>, WriteStorage<'a, DropEvent>, WriteStorage<'a, UseItemEvent>, WriteStorage<'a, EquipEvent>, WriteStorage<'a, CloseDoorEvent>, ReadStorage<'a, Acting>);
+
+    fn run(&mut self, (entities, mut dispatcher, player, rng, properties, mut effects, mut hp_change, mut moves, mut shoot, mut throw, mut pick_up, mut drop, mut use_item, mut equip, mut close_door, acting): Self::SystemData) {
+        use specs::Join;
+
+        let mut rng = rng.0.lock().unwrap();
+        // effects last a number of the entity's own turns, however fast it is
+        for (ent, effects, prop, _) in (&*entities, &mut effects, &properties, &acting).join() {
+            if !prop.alive {
+                effects.0.clear();
+                continue;
+            }
+            for effect in effects.0.iter_mut() {
+                match effect.kind {
+                    EffectKind::Poison | EffectKind::Regeneration => {
+                        let (amount, cause) = if effect.kind == EffectKind::Poison {
+                            (-effect.power, HpCause::Poison)
+                        } else {
+                            (effect.power, HpCause::Healing)
+                        };
+                        let change = hp_change.get(ent).map_or(0, |c| c.0);
+                        hp_change.insert(ent, HpChange(change + amount, cause)).unwrap();
+                    }
+                    EffectKind::Confusion => {
+                        if moves.get(ent).is_some() {
+                            let (dx, dy) = (rng.get_int(-1, 1), rng.get_int(-1, 1));
+                            moves.insert(ent, MoveEvent(dx, dy)).unwrap();
+                        }
+                    }
+                    EffectKind::Sleep => {
+                        // whatever was asked for, the turn is slept away
+                        moves.remove(ent);
+                        shoot.remove(ent);
+                        throw.remove(ent);
+                        pick_up.remove(ent);
+                        drop.remove(ent);
+                        use_item.remove(ent);
+                        equip.remove(ent);
+                        close_door.remove(ent);
+                    }
+                }
+                effect.turns -= 1;
+                if effect.turns <= 0 {
+                    dispatcher.dispatch(observer::Event::Log(ent, format!("{} no longer {}.", subject(ent, player.0, &prop.name), effect.kind.adjective()), tcod::colors::LIGHT_GREY));
+                }
+            }
+            effects.0.retain(|effect| effect.turns > 0);
         }
     }
 }
 
+/// Damage dealt by a trap to whoever steps on it
+const TRAP_DAMAGE: i32 = 3;
+/// The player forces a locked door open once in this many tries
+const FORCE_LOCK_ODDS: i32 = 3;
+
 struct HandleMoveEvents;
 impl<'a> System<'a> for HandleMoveEvents {
-    type SystemData = (specs::Entities<'a>, WriteExpect<'a, map::Map>, WriteStorage<'a, Position>, WriteStorage<'a, MoveEvent>, ReadExpect<'a, Player>, ReadStorage<'a, Properties>, WriteStorage<'a, MeleeEvent>);
+    type SystemData = (specs::Entities<'a>, WriteExpect<'a, map::Map>, WriteStorage<'a, Position>, WriteStorage<'a, MoveEvent>, ReadExpect<'a, Player>, ReadStorage<'a, Properties>, WriteStorage<'a, MeleeEvent>, WriteStorage<'a, HpChange>, WriteExpect<'a, observer::Dispatcher<'static>>, ReadExpect<'a, Rng>, WriteStorage<'a, CloseDoorEvent>);
 
-    fn run(&mut self, (entities, mut map, mut pos, mut event_storage, player, properties, mut melee_storage): Self::SystemData) {
+    fn run(&mut self, (entities, mut map, mut pos, mut event_storage, player, properties, mut melee_storage, mut hp_change, mut dispatcher, rng, mut close_door): Self::SystemData) {
         use specs::Join;
 
+        let mut rng = rng.0.lock().unwrap();
         let mut to_remove = Vec::new();
 
-        let positions: Vec<(specs::Entity, i32,i32)> = (&*entities, &pos, &properties).join().filter_map(|e| {
-            if e.2.blocks && e.0 != player.0 {
+        let mut positions: Vec<(specs::Entity, i32,i32)> = (&*entities, &pos, &properties).join().filter_map(|e| {
+            if e.2.blocks {
                 Some((e.0, e.1.x, e.1.y))
             } else {
                 None
@@ -218,40 +1117,117 @@ impl<'a> System<'a> for HandleMoveEvents {
         }).collect();
 
         for (ent, pos, event) in (&*entities, &mut pos, &mut event_storage).join() {
-            let other = positions.iter().find(|(_, x, y)| { (*x, *y) == (pos.x + event.0, pos.y + event.1) });
-            if map.can_walk(pos.x + event.0, pos.y + event.1) && other == None {
+            let (x, y) = (pos.x + event.0, pos.y + event.1);
+            let other = positions.iter().find(|(e, ox, oy)| { *e != ent && (*ox, *oy) == (x, y) }).cloned();
+            let name = properties.get(ent).map_or(String::new(), |prop| prop.name.clone());
+            if map.can_walk(x, y) && other == None {
                 pos.old_x = pos.x;
                 pos.old_y = pos.y;
-                pos.x += event.0;
-                pos.y += event.1;
-                if ent == player.0 {
-                    map.recompute_fov(pos.x, pos.y);
+                pos.x = x;
+                pos.y = y;
+                // keep the blockers up to date so two monsters can't end up on the same tile
+                if let Some(blocker) = positions.iter_mut().find(|(e, _, _)| *e == ent) {
+                    blocker.1 = pos.x;
+                    blocker.2 = pos.y;
+                }
+                if let map::TileKind::Trap { .. } = map.tile_at(x, y) {
+                    map.set_tile(x, y, map::TileKind::Trap { revealed: true });
+                    let change = hp_change.get(ent).map_or(0, |c| c.0);
+                    hp_change.insert(ent, HpChange(change - TRAP_DAMAGE, HpCause::Trap)).unwrap();
+                    let color = if ent == player.0 { tcod::colors::LIGHT_RED } else { tcod::colors::WHITE };
+                    dispatcher.dispatch(observer::Event::Log(ent, format!("{} triggered a trap for {} damage!", name, TRAP_DAMAGE), color));
                 }
             } else if let Some(other) = other {
-                // this is considered as a melee attack
-                melee_storage.insert(other.0, MeleeEvent(ent)).unwrap();
+                // this is considered as a melee attack, several attackers can gang up on the same target.
+                // Monsters don't fight each other, one stuck behind another just loses its move.
+                if ent == player.0 || other.0 == player.0 {
+                    if melee_storage.get(other.0).is_none() {
+                        melee_storage.insert(other.0, MeleeEvent(Vec::new())).unwrap();
+                    }
+                    melee_storage.get_mut(other.0).unwrap().0.push(ent);
+                }
+            } else {
+                // bumping into a tile might do something to it, monsters only know how to open doors
+                match map.tile_at(x, y) {
+                    map::TileKind::Door { open: false } => {
+                        map.set_tile(x, y, map::TileKind::Door { open: true });
+                        if ent == player.0 {
+                            dispatcher.dispatch(observer::Event::Log(ent, "You open the door.".into(), tcod::colors::WHITE));
+                        } else if map.is_in_fov(pos.x, pos.y) {
+                            dispatcher.dispatch(observer::Event::Log(ent, format!("{} opens a door.", name), tcod::colors::WHITE));
+                        }
+                    }
+                    map::TileKind::LockedDoor if ent == player.0 => {
+                        if rng.get_int(1, FORCE_LOCK_ODDS) == 1 {
+                            map.set_tile(x, y, map::TileKind::Door { open: true });
+                            dispatcher.dispatch(observer::Event::Log(ent, "You force the lock and the door swings open.".into(), tcod::colors::WHITE));
+                        } else {
+                            dispatcher.dispatch(observer::Event::Log(ent, "The door is locked.".into(), tcod::colors::LIGHT_GREY));
+                        }
+                    }
+                    map::TileKind::Rubble if ent == player.0 => {
+                        map.set_tile(x, y, map::TileKind::Floor);
+                        dispatcher.dispatch(observer::Event::Log(ent, "You clear the rubble away.".into(), tcod::colors::WHITE));
+                    }
+                    _ => {}
+                }
             }
             to_remove.push(ent);
         }
         for e in to_remove {
             event_storage.remove(e);
         }
+
+        // after the moves, so whoever just stepped into the doorway keeps it open
+        let closing: Vec<(specs::Entity, i32, i32)> = (&*entities, &close_door).join().map(|(e, door)| (e, door.0, door.1)).collect();
+        for (ent, x, y) in closing {
+            close_door.remove(ent);
+            if map.tile_at(x, y) != (map::TileKind::Door { open: true }) {
+                continue;
+            }
+            if (&pos).join().any(|p| (p.x, p.y) == (x, y)) {
+                dispatcher.dispatch(observer::Event::Log(ent, "Something is in the way of the door.".into(), tcod::colors::LIGHT_GREY));
+            } else {
+                map.set_tile(x, y, map::TileKind::Door { open: false });
+                dispatcher.dispatch(observer::Event::Log(ent, "You close the door.".into(), tcod::colors::WHITE));
+            }
+        }
+    }
+}
+
+/// Lights the floor from every light source on it and works out what the player sees by it
+struct Lighting;
+impl<'a> System<'a> for Lighting {
+    type SystemData = (WriteExpect<'a, map::Map>, ReadExpect<'a, Player>, ReadStorage<'a, Position>, ReadStorage<'a, LightSource>);
+
+    fn run(&mut self, (mut map, player, position, light): Self::SystemData) {
+        use specs::Join;
+
+        let lights: Vec<((i32, i32), LightSource)> = (&position, &light).join()
+            .map(|(pos, light)| ((pos.x, pos.y), *light))
+            .collect();
+        if let Some(pos) = position.get(player.0) {
+            map.recompute_fov(pos.x, pos.y, &lights);
+        }
     }
 }
 
 pub struct Turns(pub i64);
 
+/// Current floor of the dungeon, starting at 1
+pub struct Depth(pub i32);
+
 pub struct Rng(pub Arc<Mutex<tcod::random::Rng>>);
 
 pub struct Player(pub specs::Entity);
 
-pub struct DisplayConsole(Arc<Mutex<tcod::console::Offscreen>>);
+pub struct DisplayConsole(Arc<Mutex<Grid>>);
 impl DisplayConsole {
-    pub fn get<'ret, 'me:'ret>(&'me self) -> std::sync::MutexGuard<'ret, tcod::console::Offscreen> {
+    pub fn get<'ret, 'me:'ret>(&'me self) -> std::sync::MutexGuard<'ret, Grid> {
         self.0.lock().unwrap()
     }
 
-    pub fn get_mut<'ret, 'me:'ret>(&'me mut self) -> std::sync::MutexGuard<'ret, tcod::console::Offscreen> {
+    pub fn get_mut<'ret, 'me:'ret>(&'me mut self) -> std::sync::MutexGuard<'ret, Grid> {
         self.0.lock().unwrap()
     }
 }
@@ -260,40 +1236,566 @@ pub fn create_player(world: &mut World, x: i32, y: i32) -> specs::Entity {
     world.create_entity()
         .with(Position::new(x, y, 1))
         .with(Displayable::new('@', tcod::colors::WHITE))
-        .with(Properties::new("You", true, true, 30, 30, |props, display| {
-            props.alive = false;
-            display.char = '%';
-            display.color = tcod::colors::DARK_RED;
-        }))
-        .with(Fighter::new(5, 2))
+        .with(Properties::new("You", true, true, 30, 30, DeathCallback::Player))
+        .with(Fighter::new(5, 2, 0))
+        .with(Inventory(Vec::new()))
+        .with(Experience { xp: 0, level: 1, kills: 0 })
+        .with(Energy { speed: NORMAL_SPEED, energy: ENERGY_THRESHOLD })
+        .with(TORCH)
         .build()
 }
 
-pub fn create_npc<FN>(world: &mut World, x: i32, y: i32, c: char, name: &str, max_hp: i32, hp: i32, fighter: Option<Fighter>, f: FN, color: tcod::colors::Color)
-    where FN: Fn(&mut Properties, &mut Displayable) + std::marker::Send + 'static
-    {
-    let e = world.create_entity()
+/// A fire burning on the floor, lighting up the room around it
+pub fn create_brazier(world: &mut World, x: i32, y: i32) -> specs::Entity {
+    world.create_entity()
+        .with(Position::new(x, y, 0))
+        .with(Displayable::new('&', tcod::colors::ORANGE))
+        .with(Properties {
+            name: "brazier".into(),
+            blocks: false,
+            alive: false,
+            max_hp: 0,
+            hp: 0,
+            death_callback: None,
+        })
+        .with(BRAZIER)
+        .build()
+}
+
+pub fn create_item(world: &mut World, x: i32, y: i32, kind: ItemKind) -> specs::Entity {
+    let (c, color) = kind.appearance();
+    world.create_entity()
         .with(Position::new(x, y, 0))
         .with(Displayable::new(c, color))
-        .with(Properties::new(name, true, true, max_hp, hp, f))
+        .with(Properties {
+            name: kind.name().into(),
+            blocks: false,
+            alive: false,
+            max_hp: 0,
+            hp: 0,
+            death_callback: None,
+        })
+        .with(Item(kind))
+        .build()
+}
+
+/// Names of the items carried by `entity`, in inventory order
+pub fn inventory_of(world: &World, entity: specs::Entity) -> Vec<(specs::Entity, String)> {
+    let properties = world.read_storage::<Properties>();
+    let equipped = world.read_storage::<Equipped>();
+    match world.read_storage::<Inventory>().get(entity) {
+        Some(inventory) => inventory.0.iter()
+            .map(|item| {
+                let name = properties.get(*item).map_or(String::new(), |p| p.name.clone());
+                match equipped.get(*item) {
+                    Some(eq) => (*item, format!("{} (on {})", name, eq.0.name())),
+                    None => (*item, name),
+                }
+            })
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+/// Spawns the monster described by the template `id` of the `MonsterBook` resource
+pub fn create_npc(world: &mut World, id: &str, x: i32, y: i32) -> Option<specs::Entity> {
+    let template = match world.read_resource::<monsters::MonsterBook>().get(id) {
+        Some(template) => template.clone(),
+        None => return None,
+    };
+    let death = DeathCallback::Monster { corpse: template.corpse.glyph, color: template.corpse.color };
+    let e = world.create_entity()
+        .with(Position::new(x, y, 0))
+        .with(Displayable::new(template.glyph, template.color))
+        .with(Properties::new(&template.name, true, true, template.hp, template.hp, death))
+        .with(Fighter::new(template.attack, template.defense, template.xp))
+        .with(Ai)
+        .with(Energy { speed: template.speed, energy: 0 })
         .build();
-    if let Some(f) = fighter {
-        world.write_storage::<Fighter>().insert(e, f).unwrap();
+    if let Some(hit) = template.on_hit {
+        let effect = StatusEffect { kind: hit.effect, turns: hit.turns, power: hit.power };
+        world.write_storage::<OnHit>().insert(e, OnHit(effect)).unwrap();
+    }
+    if let Some(light) = template.light {
+        world.write_storage::<LightSource>().insert(e, light).unwrap();
+    }
+    Some(e)
+}
+
+/// Whether `entity` earned enough experience to level up
+pub fn can_level_up(world: &World, entity: specs::Entity) -> bool {
+    world.read_storage::<Experience>().get(entity)
+        .map_or(false, |exp| exp.xp >= xp_to_next_level(exp.level))
+}
+
+pub fn level_up(world: &mut World, entity: specs::Entity, choice: LevelUpChoice) {
+    let mut experience = world.write_storage::<Experience>();
+    let exp = match experience.get_mut(entity) {
+        Some(exp) => exp,
+        None => return,
+    };
+    exp.xp -= xp_to_next_level(exp.level);
+    exp.level += 1;
+    match choice {
+        LevelUpChoice::Constitution => {
+            if let Some(prop) = world.write_storage::<Properties>().get_mut(entity) {
+                prop.max_hp += 20;
+                prop.hp += 20;
+            }
+        }
+        LevelUpChoice::Strength => {
+            if let Some(fighter) = world.write_storage::<Fighter>().get_mut(entity) {
+                fighter.attack += 1;
+            }
+        }
+        LevelUpChoice::Agility => {
+            if let Some(fighter) = world.write_storage::<Fighter>().get_mut(entity) {
+                fighter.defense += 1;
+            }
+        }
+    }
+}
+
+/// Everything the character sheet shows about an entity
+pub struct Stats {
+    pub name: String,
+    pub level: i32,
+    pub xp: i32,
+    pub xp_to_next_level: i32,
+    pub hp: i32,
+    pub max_hp: i32,
+    /// attack and defense include the bonuses of the equipment
+    pub attack: i32,
+    pub defense: i32,
+    pub base_attack: i32,
+    pub base_defense: i32,
+    pub kills: i32,
+}
+
+pub fn stats_of(world: &World, entity: specs::Entity) -> Option<Stats> {
+    let properties = world.read_storage::<Properties>();
+    let fighter = world.read_storage::<Fighter>();
+    let experience = world.read_storage::<Experience>();
+    let effective = effective_stats(
+        entity,
+        &fighter,
+        &world.read_storage::<Inventory>(),
+        &world.read_storage::<Item>(),
+        &world.read_storage::<Equipped>(),
+    );
+    match (properties.get(entity), fighter.get(entity), effective) {
+        (Some(prop), Some(fighter), Some((attack, defense))) => {
+            let (xp, level, kills) = experience.get(entity).map_or((0, 1, 0), |e| (e.xp, e.level, e.kills));
+            Some(Stats {
+                name: prop.name.clone(),
+                level: level,
+                xp: xp,
+                xp_to_next_level: xp_to_next_level(level),
+                hp: prop.hp,
+                max_hp: prop.max_hp,
+                attack: attack,
+                defense: defense,
+                base_attack: fighter.attack,
+                base_defense: fighter.defense,
+                kills: kills,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// An item `entity` carries and could wear or wield
+pub struct EquipmentEntry {
+    pub item: specs::Entity,
+    pub name: String,
+    pub bonus: Bonus,
+    pub equipped: bool,
+}
+
+/// Equippable items carried by `entity`, in inventory order
+pub fn equipment_of(world: &World, entity: specs::Entity) -> Vec<EquipmentEntry> {
+    let properties = world.read_storage::<Properties>();
+    let items = world.read_storage::<Item>();
+    let equipped = world.read_storage::<Equipped>();
+    let inventories = world.read_storage::<Inventory>();
+    let inventory = match inventories.get(entity) {
+        Some(inventory) => inventory,
+        None => return Vec::new(),
+    };
+    inventory.0.iter()
+        .filter_map(|&item| {
+            let bonus = items.get(item).and_then(|i| i.0.bonus())?;
+            Some(EquipmentEntry {
+                item: item,
+                name: properties.get(item).map_or(String::new(), |p| p.name.clone()),
+                bonus: bonus,
+                equipped: equipped.get(item).is_some(),
+            })
+        })
+        .collect()
+}
+
+/// Draws the map and the entities on the display console without running a turn
+pub fn draw(world: &World) {
+    use specs::RunNow;
+    Print.run_now(&world.res);
+}
+
+/// Lights the current floor and updates what the player sees without running a turn
+pub fn update_lighting(world: &World) {
+    use specs::RunNow;
+    Lighting.run_now(&world.res);
+}
+
+/// The saved form of an entity: every component it has that is worth keeping
+#[derive(Serialize, Deserialize)]
+pub struct EntityRecord {
+    player: bool,
+    position: Option<Position>,
+    dormant: Option<Dormant>,
+    properties: Option<Properties>,
+    displayable: Option<Displayable>,
+    fighter: Option<Fighter>,
+    experience: Option<Experience>,
+    ai: bool,
+    item: Option<Item>,
+    equipped: Option<Equipped>,
+    status_effects: Option<StatusEffects>,
+    on_hit: Option<OnHit>,
+    energy: Option<Energy>,
+    light: Option<LightSource>,
+    /// indices of the carried items in the saved entity list
+    inventory: Option<Vec<usize>>,
+}
+
+/// 64-bit FNV-1a, whose output is fixed unlike std's `DefaultHasher` so hashes saved in replays stay
+/// comparable across Rust releases. Integers are fed little-endian and `usize` as 64 bits, the same
+/// on every platform.
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    fn new() -> Fnv1a {
+        Fnv1a(Fnv1a::OFFSET_BASIS)
+    }
+
+    fn write_le(&mut self, value: u64, bytes: usize) {
+        for i in 0..bytes {
+            self.write_u8((value >> (8 * i)) as u8);
+        }
+    }
+}
+
+impl std::hash::Hasher for Fnv1a {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.write_u8(byte);
+        }
+    }
+
+    fn write_u8(&mut self, byte: u8) {
+        self.0 = (self.0 ^ byte as u64).wrapping_mul(Fnv1a::PRIME);
+    }
+
+    fn write_u16(&mut self, value: u16) {
+        self.write_le(value as u64, 2);
+    }
+
+    fn write_u32(&mut self, value: u32) {
+        self.write_le(value as u64, 4);
+    }
+
+    fn write_u64(&mut self, value: u64) {
+        self.write_le(value, 8);
+    }
+
+    fn write_usize(&mut self, value: usize) {
+        self.write_le(value as u64, 8);
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Hash of everything a turn can change, two games in the same state have the same hash
+pub fn state_hash(world: &World) -> u64 {
+    use specs::Join;
+    use std::hash::{Hash, Hasher};
+
+    let mut hasher = Fnv1a::new();
+    world.read_resource::<Turns>().0.hash(&mut hasher);
+    world.read_resource::<Depth>().0.hash(&mut hasher);
+
+    let entities = world.entities();
+    let position = world.read_storage::<Position>();
+    let dormant = world.read_storage::<Dormant>();
+    let properties = world.read_storage::<Properties>();
+    let inventory = world.read_storage::<Inventory>();
+    let equipped = world.read_storage::<Equipped>();
+    let effects = world.read_storage::<StatusEffects>();
+    let energy = world.read_storage::<Energy>();
+    for e in (&*entities).join() {
+        e.id().hash(&mut hasher);
+        if let Some(pos) = position.get(e) {
+            (pos.x, pos.y).hash(&mut hasher);
+        }
+        if let Some(d) = dormant.get(e) {
+            (d.depth, d.position.x, d.position.y).hash(&mut hasher);
+        }
+        if let Some(prop) = properties.get(e) {
+            (&prop.name, prop.hp, prop.max_hp, prop.alive, prop.blocks).hash(&mut hasher);
+        }
+        if let Some(inv) = inventory.get(e) {
+            for item in inv.0.iter() {
+                item.id().hash(&mut hasher);
+            }
+        }
+        if let Some(eq) = equipped.get(e) {
+            eq.0.name().hash(&mut hasher);
+        }
+        if let Some(effects) = effects.get(e) {
+            for effect in effects.0.iter() {
+                (effect.kind.adjective(), effect.turns, effect.power).hash(&mut hasher);
+            }
+        }
+        if let Some(energy) = energy.get(e) {
+            (energy.speed, energy.energy).hash(&mut hasher);
+        }
+    }
+    hasher.finish()
+}
+
+pub fn save_entities(world: &World, player: specs::Entity) -> Vec<EntityRecord> {
+    use specs::Join;
+
+    let entities = world.entities();
+    let position = world.read_storage::<Position>();
+    let dormant = world.read_storage::<Dormant>();
+    let properties = world.read_storage::<Properties>();
+    let displayable = world.read_storage::<Displayable>();
+    let fighter = world.read_storage::<Fighter>();
+    let experience = world.read_storage::<Experience>();
+    let ai = world.read_storage::<Ai>();
+    let item = world.read_storage::<Item>();
+    let inventory = world.read_storage::<Inventory>();
+    let equipped = world.read_storage::<Equipped>();
+    let effects = world.read_storage::<StatusEffects>();
+    let on_hit = world.read_storage::<OnHit>();
+    let energy = world.read_storage::<Energy>();
+    let light = world.read_storage::<LightSource>();
+
+    let saved: Vec<specs::Entity> = (&*entities).join().collect();
+    let index = |e: &specs::Entity| saved.iter().position(|s| s == e);
+
+    saved.iter().map(|&e| {
+        EntityRecord {
+            player: e == player,
+            position: position.get(e).map(|p| Position { x: p.x, y: p.y, old_x: p.old_x, old_y: p.old_y, z: p.z }),
+            dormant: dormant.get(e).map(|d| Dormant {
+                depth: d.depth,
+                position: Position { x: d.position.x, y: d.position.y, old_x: d.position.old_x, old_y: d.position.old_y, z: d.position.z },
+            }),
+            properties: properties.get(e).map(|p| Properties {
+                name: p.name.clone(),
+                blocks: p.blocks,
+                alive: p.alive,
+                max_hp: p.max_hp,
+                hp: p.hp,
+                death_callback: p.death_callback,
+            }),
+            displayable: displayable.get(e).map(|d| Displayable::new(d.char, d.color)),
+            fighter: fighter.get(e).map(|f| Fighter::new(f.attack, f.defense, f.xp)),
+            experience: experience.get(e).map(|x| Experience { xp: x.xp, level: x.level, kills: x.kills }),
+            ai: ai.get(e).is_some(),
+            item: item.get(e).map(|i| Item(i.0)),
+            equipped: equipped.get(e).map(|eq| Equipped(eq.0)),
+            status_effects: effects.get(e).map(|s| StatusEffects(s.0.clone())),
+            on_hit: on_hit.get(e).map(|h| OnHit(h.0)),
+            energy: energy.get(e).map(|x| Energy { speed: x.speed, energy: x.energy }),
+            light: light.get(e).cloned(),
+            inventory: inventory.get(e).map(|i| i.0.iter().filter_map(&index).collect()),
+        }
+    }).collect()
+}
+
+/// Recreates the saved entities, returns the player if there was one
+pub fn load_entities(world: &mut World, records: Vec<EntityRecord>) -> Option<specs::Entity> {
+    let mut player = None;
+    // create every entity first so inventories can refer to any of them
+    let created: Vec<specs::Entity> = records.iter().map(|_| world.create_entity().build()).collect();
+    for (e, record) in created.iter().cloned().zip(records) {
+        if let Some(c) = record.position { world.write_storage::<Position>().insert(e, c).unwrap(); }
+        if let Some(c) = record.dormant { world.write_storage::<Dormant>().insert(e, c).unwrap(); }
+        if let Some(c) = record.properties { world.write_storage::<Properties>().insert(e, c).unwrap(); }
+        if let Some(c) = record.displayable { world.write_storage::<Displayable>().insert(e, c).unwrap(); }
+        if let Some(c) = record.fighter { world.write_storage::<Fighter>().insert(e, c).unwrap(); }
+        if let Some(c) = record.experience { world.write_storage::<Experience>().insert(e, c).unwrap(); }
+        if record.ai { world.write_storage::<Ai>().insert(e, Ai).unwrap(); }
+        if let Some(c) = record.item { world.write_storage::<Item>().insert(e, c).unwrap(); }
+        if let Some(c) = record.equipped { world.write_storage::<Equipped>().insert(e, c).unwrap(); }
+        if let Some(c) = record.status_effects { world.write_storage::<StatusEffects>().insert(e, c).unwrap(); }
+        if let Some(c) = record.on_hit { world.write_storage::<OnHit>().insert(e, c).unwrap(); }
+        if let Some(c) = record.energy { world.write_storage::<Energy>().insert(e, c).unwrap(); }
+        if let Some(c) = record.light { world.write_storage::<LightSource>().insert(e, c).unwrap(); }
+        if let Some(c) = record.inventory {
+            let items = c.into_iter().filter_map(|i| created.get(i).cloned()).collect();
+            world.write_storage::<Inventory>().insert(e, Inventory(items)).unwrap();
+        }
+        if record.player {
+            player = Some(e);
+        }
+    }
+    player
+}
+
+pub fn position_of(world: &World, entity: specs::Entity) -> Option<(i32, i32)> {
+    world.read_storage::<Position>().get(entity).map(|pos| (pos.x, pos.y))
+}
+
+/// How `entity` died, `None` while it lives
+pub fn death_of(world: &World, entity: specs::Entity) -> Option<String> {
+    world.read_storage::<Death>().get(entity).map(|death| death.0.clone())
+}
+
+/// Whether nothing else can stand on the same tile as `entity`
+pub fn blocks(world: &World, entity: specs::Entity) -> bool {
+    world.read_storage::<Properties>().get(entity).map_or(false, |prop| prop.blocks)
+}
+
+/// An open door next to `entity`, if there is one
+pub fn open_door_near(world: &World, entity: specs::Entity) -> Option<(i32, i32)> {
+    let (x, y) = position_of(world, entity)?;
+    let map = world.read_resource::<map::Map>();
+    let neighbours = [(0, -1), (0, 1), (-1, 0), (1, 0), (-1, -1), (1, -1), (-1, 1), (1, 1)];
+    neighbours
+        .iter()
+        .map(|&(dx, dy)| (x + dx, y + dy))
+        .find(|&(nx, ny)| map.tile_at(nx, ny) == map::TileKind::Door { open: true })
+}
+
+/// Range of the ranged weapon `entity` has equipped
+pub fn shooting_range_of(world: &World, entity: specs::Entity) -> Option<i32> {
+    ranged_weapon(
+        entity,
+        &world.read_storage::<Inventory>(),
+        &world.read_storage::<Item>(),
+        &world.read_storage::<Equipped>(),
+    ).and_then(|kind| kind.ranged()).map(|(range, _)| range)
+}
+
+/// Living fighters the player can see with their names, closest to `from` first
+pub fn visible_targets(world: &World, from: (i32, i32)) -> Vec<(i32, i32, String)> {
+    use specs::Join;
+    let map = world.read_resource::<map::Map>();
+    let position = world.read_storage::<Position>();
+    let properties = world.read_storage::<Properties>();
+    let fighters = world.read_storage::<Fighter>();
+    let mut targets: Vec<(i32, i32, String)> = (&position, &properties, &fighters).join()
+        .filter(|&(pos, prop, _)| prop.alive && (pos.x, pos.y) != from && map.is_in_fov(pos.x, pos.y))
+        .map(|(pos, prop, _)| (pos.x, pos.y, prop.name.clone()))
+        .collect();
+    targets.sort_by_key(|&(x, y, _)| (x - from.0).pow(2) + (y - from.1).pow(2));
+    targets
+}
+
+/// Whether it is the turn of `entity`, or it can't act anymore
+pub fn is_ready(world: &World, entity: specs::Entity) -> bool {
+    let alive = world.read_storage::<Properties>().get(entity).map_or(false, |p| p.alive);
+    let ready = world.read_storage::<Energy>().get(entity).map_or(true, |e| e.energy >= ENERGY_THRESHOLD);
+    !alive || ready
+}
+
+/// Names of the entities standing on a tile the player can see
+pub fn names_at(world: &World, x: i32, y: i32) -> Vec<String> {
+    use specs::Join;
+    {
+        let map = world.read_resource::<map::Map>();
+        if x < 0 || y < 0 || x >= map.width() || y >= map.height() || !map.is_in_fov(x, y) {
+            return Vec::new();
+        }
+    }
+    let position = world.read_storage::<Position>();
+    let properties = world.read_storage::<Properties>();
+    (&position, &properties).join()
+        .filter(|&(pos, _)| pos.x == x && pos.y == y)
+        .map(|(_, prop)| prop.name.clone())
+        .collect()
+}
+
+pub fn place_entity(world: &mut World, entity: specs::Entity, x: i32, y: i32) {
+    if let Some(pos) = world.write_storage::<Position>().get_mut(entity) {
+        pos.x = x;
+        pos.y = y;
+        pos.old_x = x;
+        pos.old_y = y;
+    }
+}
+
+/// Puts every entity of the current floor but `keep` to sleep
+pub fn leave_floor(world: &mut World, depth: i32, keep: specs::Entity) {
+    use specs::Join;
+
+    let entities = world.entities();
+    let mut position = world.write_storage::<Position>();
+    let mut dormant = world.write_storage::<Dormant>();
+
+    let leaving: Vec<specs::Entity> = (&*entities, &position).join()
+        .map(|(e, _)| e)
+        .filter(|e| *e != keep)
+        .collect();
+    for e in leaving {
+        if let Some(pos) = position.remove(e) {
+            dormant.insert(e, Dormant { depth: depth, position: pos }).unwrap();
+        }
+    }
+}
+
+/// Wakes up every entity that was left on the floor at `depth`
+pub fn enter_floor(world: &mut World, depth: i32) {
+    use specs::Join;
+
+    let entities = world.entities();
+    let mut position = world.write_storage::<Position>();
+    let mut dormant = world.write_storage::<Dormant>();
+
+    let waking: Vec<specs::Entity> = (&*entities, &dormant).join()
+        .filter(|(_, d)| d.depth == depth)
+        .map(|(e, _)| e)
+        .collect();
+    for e in waking {
+        if let Some(d) = dormant.remove(e) {
+            position.insert(e, d.position).unwrap();
+        }
     }
 }
 
-pub fn create_world<'a, 'b>(con: tcod::console::Offscreen) -> (World, Dispatcher<'a, 'b>) {
+pub fn create_world<'a, 'b>(con: Grid) -> (World, Dispatcher<'a, 'b>) {
     let mut world = World::new();
     world.register::<Position>();
     world.register::<Displayable>();
     world.register::<MoveEvent>();
+    world.register::<Dormant>();
     world.add_resource(DisplayConsole(Arc::new(Mutex::new(con))));
     world.add_resource(Turns(0));
+    world.add_resource(Depth(1));
     world.add_resource(observer::Dispatcher::new());
     let mut dispatcher = DispatcherBuilder::new()
-        .with(HandleMoveEvents, "move_event", &[])
-        .with(HandleMelee, "melee_event", &["move_event"])
-        .with(HandleHpChange, "hp_change", &["melee_event"])
+        .with(Schedule, "schedule", &[])
+        .with(MonsterAi, "ai", &["schedule"])
+        // runs once the actions of the turn are known, so confusion and sleep can alter them
+        .with(HandleStatusEffects, "status_effects", &["ai"])
+        .with(HandleMoveEvents, "move_event", &["status_effects"])
+        // equipment changes made this turn already count in the fights
+        .with(HandleInventory, "inventory", &["move_event"])
+        .with(HandleMelee, "melee_event", &["inventory"])
+        .with(HandleRanged, "ranged", &["melee_event"])
+        .with(HandleHpChange, "hp_change", &["ranged"])
+        .with(PassTime, "pass_time", &["hp_change"])
+        // what can be seen depends on where everyone ended up this tick
+        .with(Lighting, "lighting", &["hp_change"])
         .with_thread_local(Print).build();
     dispatcher.setup(&mut world.res);
     (world, dispatcher)
@@ -0,0 +1,31 @@
+use components::{EquipmentEntry, SLOTS};
+use menu;
+
+const EQUIPMENT_WIDTH: i32 = 50;
+
+/// What the player wears in each slot, and the items they could put on instead.
+///
+/// Picking an item with its letter or the mouse writes its index in `choice` and pops the menu,
+/// the game then equips it or takes it off.
+pub fn menu(entries: &[EquipmentEntry], choice: menu::Choice) -> menu::Menu {
+    let mut header = vec!["Equipment".to_string(), String::new()];
+    for slot in SLOTS.iter() {
+        let worn = entries
+            .iter()
+            .find(|entry| entry.equipped && entry.bonus.slot == *slot)
+            .map_or("nothing".to_string(), |entry| {
+                format!("{} ({})", entry.name, entry.bonus.description())
+            });
+        header.push(format!("{:<8}{}", format!("{}:", slot.name()), worn));
+    }
+    header.push(String::new());
+    header.push("Press the key next to an item to equip or take it off.".into());
+    let options = entries
+        .iter()
+        .map(|entry| {
+            let status = if entry.equipped { ", equipped" } else { "" };
+            format!("{} ({}{})", entry.name, entry.bonus.slot.name(), status)
+        })
+        .collect();
+    menu::Menu::new("", options, EQUIPMENT_WIDTH, choice).with_header(header)
+}
@@ -2,17 +2,173 @@ use components::*;
 use specs::{Dispatcher, Entity, World};
 use state_machine::{Event, State, Transition};
 use tcod;
-use tcod::Console;
 
+use character;
+use equipment;
+use game_over;
+use keymap;
+use keymap::Action;
 use map;
-use rect;
+use mapgen;
+use menu;
+use save;
+use screen::{Grid, Screen};
+use targeting;
+use vaults;
 
+use messages;
+use monsters;
 use observer;
-use observer::*;
 
+use std::cell::Cell;
+use std::rc::Rc;
 use std::sync::{Arc, Mutex};
 
 const MAX_ROOM_MONSTERS: i32 = 3;
+const MAX_ROOM_ITEMS: i32 = 2;
+/// One room in this many is lit by a brazier
+const BRAZIER_ODDS: i32 = 4;
+/// Floors carved before giving up on getting a sound one
+const GENERATION_ATTEMPTS: u32 = 10;
+
+/// Items that can be found, with their weight and the first depth they show up at
+const ITEM_TABLE: &[(ItemKind, i32, i32)] = &[
+    (ItemKind::HealingPotion, 70, 1),
+    (ItemKind::Dagger, 10, 1),
+    (ItemKind::LeatherArmor, 8, 1),
+    (ItemKind::Shield, 6, 2),
+    (ItemKind::Sword, 5, 3),
+    (ItemKind::RingOfProtection, 3, 4),
+    (ItemKind::Shortbow, 4, 2),
+    (ItemKind::PotionOfRegeneration, 6, 1),
+    (ItemKind::ScrollOfConfusion, 8, 2),
+    (ItemKind::ScrollOfSleep, 6, 2),
+];
+const INVENTORY_WIDTH: i32 = 50;
+const LEVEL_SCREEN_WIDTH: i32 = 40;
+
+/// Monsters get more numerous the deeper the player goes
+fn max_room_monsters(depth: i32) -> i32 {
+    MAX_ROOM_MONSTERS + (depth - 1) / 2
+}
+
+/// Weighted random pick among the items found at `depth`
+fn pick_item(rng: &mut tcod::random::Rng, depth: i32) -> ItemKind {
+    let available = ITEM_TABLE.iter().filter(|&&(_, _, min_depth)| depth >= min_depth);
+    let total: i32 = available.clone().map(|&(_, weight, _)| weight).sum();
+    let mut roll = rng.get_int(0, total - 1);
+    for &(kind, weight, _) in available {
+        if roll < weight {
+            return kind;
+        }
+        roll -= weight;
+    }
+    ItemKind::HealingPotion
+}
+
+/// Every floor is laid out by one of the generators, picked at random, then gets its vaults
+fn pick_generator(rng: &mut tcod::random::Rng, vaults: Vec<vaults::Vault>) -> Box<mapgen::MapGenerator> {
+    let layout: Box<mapgen::MapGenerator> = match rng.get_int(0, 2) {
+        0 => Box::new(mapgen::RandomRooms),
+        1 => Box::new(mapgen::Bsp),
+        _ => Box::new(mapgen::Caves::new()),
+    };
+    Box::new(vaults::WithVaults::new(layout, vaults))
+}
+
+fn spawn_monster(world: &mut World, rng: &mut tcod::random::Rng, depth: i32, x: i32, y: i32) -> Option<Entity> {
+    let id = world
+        .read_resource::<monsters::MonsterBook>()
+        .pick(rng, depth)
+        .map(|template| template.id.clone());
+    id.and_then(|id| create_npc(world, &id, x, y))
+}
+
+/// Carves a floor and fills it with monsters fitting its depth, drawn from the `MonsterBook`
+/// resource, and with the vaults of the `VaultBook` resource. Returns the entities spawned on it.
+fn carve_floor(world: &mut World, rng: &mut tcod::random::Rng, width: i32, height: i32, depth: i32) -> (map::Map, (i32, i32), Vec<Entity>) {
+    let mut map = map::Map::new(width, height);
+    let vaults = world.read_resource::<vaults::VaultBook>().vaults().to_vec();
+    let mut generator = pick_generator(rng, vaults);
+    let mut spawned = Vec::new();
+
+    let start = map.generate_map(&mut *generator, rng, |region: &mapgen::SpawnRegion, rng: &mut tcod::random::Rng| {
+            // vaults say exactly what goes where
+            if let mapgen::SpawnRegion::Spots { ref monsters, ref items } = *region {
+                for &(x, y) in monsters.iter() {
+                    spawned.extend(spawn_monster(world, rng, depth, x, y));
+                }
+                for &(x, y) in items.iter() {
+                    let kind = pick_item(rng, depth);
+                    spawned.push(create_item(world, x, y, kind));
+                }
+                return;
+            }
+
+            let num_monsters = rng.get_int(0, max_room_monsters(depth) + 1);
+
+            for _ in 0..num_monsters {
+                // choose random spot for this monster
+                let (x, y) = region.random_spot(rng);
+                spawned.extend(spawn_monster(world, rng, depth, x, y));
+            }
+
+            let num_items = rng.get_int(0, MAX_ROOM_ITEMS + 1);
+
+            for _ in 0..num_items {
+                let (x, y) = region.random_spot(rng);
+
+                let kind = pick_item(rng, depth);
+                spawned.push(create_item(world, x, y, kind));
+            }
+
+            if rng.get_int(1, BRAZIER_ODDS) == 1 {
+                let (x, y) = region.random_spot(rng);
+                spawned.push(create_brazier(world, x, y));
+            }
+        },
+    );
+    (map, start, spawned)
+}
+
+/// Builds a new floor of the dungeon, checked and repaired by `mapgen::check_level`.
+/// A floor beyond repair is thrown away and carved again from the next seed.
+/// Also returns notes on what had to be repaired or carved again, for the message log.
+fn generate_floor(world: &mut World, rng: &mut tcod::random::Rng, width: i32, height: i32, depth: i32) -> (map::Map, (i32, i32), Vec<String>) {
+    let seed = rng.get_int(0, std::i32::MAX) as u32;
+    let mut attempt = 0;
+    let mut notes = Vec::new();
+    loop {
+        let floor_seed = seed.wrapping_add(attempt);
+        let mut floor_rng = tcod::random::Rng::new_with_seed(tcod::random::Algo::CMWC, floor_seed);
+        let (mut map, start, spawned) = carve_floor(world, &mut floor_rng, width, height, depth);
+
+        let spots: Vec<((i32, i32), bool)> = spawned
+            .iter()
+            .map(|&e| (position_of(world, e).unwrap_or(start), blocks(world, e)))
+            .collect();
+        match mapgen::check_level(&mut map, start, &spots) {
+            Ok(repairs) => {
+                for &(i, (x, y)) in repairs.moved.iter() {
+                    place_entity(world, spawned[i], x, y);
+                }
+                if let Some(report) = repairs.report() {
+                    notes.push(format!("Floor {} (seed {}): {}.", depth, floor_seed, report));
+                }
+                return (map, start, notes);
+            }
+            Err(reason) => {
+                attempt += 1;
+                if attempt >= GENERATION_ATTEMPTS {
+                    notes.push(format!("Floor {} (seed {}) {}, kept anyway.", depth, floor_seed, reason));
+                    return (map, start, notes);
+                }
+                notes.push(format!("Floor {} (seed {}) {}, carved again.", depth, floor_seed, reason));
+                world.delete_entities(&spawned).unwrap();
+            }
+        }
+    }
+}
 
 #[derive(Clone, Copy, PartialEq)]
 enum PlayerAction {
@@ -20,142 +176,640 @@ enum PlayerAction {
     DidntTakeTurn,
 }
 
-struct Log;
+/// What to do with the item picked in the inventory menu
+#[derive(Clone, Copy, PartialEq)]
+enum InventoryAction {
+    Use,
+    Drop,
+    Throw,
+}
 
-impl Listener for Log {
-    fn notify(&mut self, event: &observer::Event) {
-        println!("{:?}", event);
-    }
+/// What to do with the tile picked in the targeting screen
+#[derive(Clone, Copy, PartialEq)]
+enum TargetAction {
+    Shoot,
+    Throw(Entity),
+    /// only tells what is there, takes no turn
+    Look,
 }
 
 use std;
-unsafe impl std::marker::Send for Log {}
 
 pub struct Game<'a, 'b> {
     world: World,
     dispatcher: Dispatcher<'a, 'b>,
     player: Entity,
     action: PlayerAction,
+    // turns the player took, the `Turns` resource counts ticks
+    player_turns: i64,
+    // floors the player is not on, indexed by depth - 1
+    floors: Vec<Option<map::Map>>,
+    messages: Arc<Mutex<messages::MessageLog>>,
+    // inventory menu waiting for the player's choice
+    inventory_menu: Option<(InventoryAction, Vec<Entity>, menu::Choice)>,
+    level_up_menu: Option<menu::Choice>,
+    // equipment screen waiting for the player's choice
+    equipment_menu: Option<(Vec<Entity>, menu::Choice)>,
+    // item picked to be thrown, the target is asked for on the next update
+    pending_throw: Option<Entity>,
+    targeting: Option<(TargetAction, targeting::Target)>,
+    game_over: Option<game_over::Choice>,
+    // a new game was asked for, this one ends on the next update
+    restart: bool,
+    // set when a new game is wanted, for whoever pushed this one to start it
+    restart_request: Option<Rc<Cell<bool>>>,
+    save_path: Option<&'static str>,
+    keymap: keymap::KeyMap,
+    // cell under the mouse cursor
+    mouse: (i32, i32),
 }
 
 impl<'a, 'b> Game<'a, 'b> {
-    pub fn new(seed: u32, screen_width: i32, screen_height: i32) -> Game<'a, 'b> {
-        let mut con = tcod::console::Offscreen::new(screen_width, screen_height);
-        con.set_default_foreground(tcod::colors::WHITE);
-
-        let mut map = map::Map::new(screen_width, screen_height - 15);
+    pub fn new(seed: u32, monsters: monsters::MonsterBook, vaults: vaults::VaultBook, keymap: keymap::KeyMap, screen_width: i32, screen_height: i32) -> Game<'a, 'b> {
+        let con = Grid::new(screen_width, screen_height);
 
         let mut rng = tcod::random::Rng::new_with_seed(tcod::random::Algo::CMWC, seed);
 
         let (mut world, dispatcher) = create_world(con);
+        world.add_resource(monsters);
+        world.add_resource(vaults);
 
-        let (x, y) = map.generate_map(&mut rng, |room: &rect::Rect, rng: &mut tcod::random::Rng| {
-                let num_monsters = rng.get_int(0, MAX_ROOM_MONSTERS + 1);
-
-                for _ in 0..num_monsters {
-                    // choose random spot for this monster
-                    let x = rng.get_int(room.x1 + 1, room.x2 - 1);
-                    let y = rng.get_int(room.y1 + 1, room.y2 - 1);
-
-                    if rng.get_int(0, 100) < 80 {
-                        create_npc(&mut world, x, y, 'o', "Orc", 10, 10, Some(Fighter::new(3, 0)), |props, display| {
-                            props.alive = false;
-                            props.blocks = false;
-                            display.char = '%';
-                            display.color = tcod::colors::DARK_RED;
-                        }, tcod::colors::DESATURATED_GREEN);
-                    } else {
-                        create_npc(&mut world, x, y, 'T', "Troll", 16, 16, Some(Fighter::new(4, 1)), |props, display| {
-                            props.alive = false;
-                            props.blocks = false;
-                            display.char = '%';
-                            display.color = tcod::colors::DARK_RED;
-                        }, tcod::colors::DARKER_GREEN);
-                    };
-                }
-            },
-        );
+        let (map, (x, y), notes) = generate_floor(&mut world, &mut rng, screen_width, screen_height - 15, 1);
 
         let player = create_player(&mut world, x, y);
 
-        let logger = Arc::new(Mutex::new(Log));
+        let game = Game::from_world(world, dispatcher, player, map, rng, vec![None], keymap);
+        game.log_notes(notes);
+        game
+    }
+
+    /// Brings back a game written by `save`
+    pub fn load(path: &str, monsters: monsters::MonsterBook, vaults: vaults::VaultBook, keymap: keymap::KeyMap, screen_width: i32, screen_height: i32) -> std::io::Result<Game<'a, 'b>> {
+        let save = save::load_game(path)?;
+
+        let con = Grid::new(screen_width, screen_height);
+
+        let (mut world, dispatcher) = create_world(con);
+        world.add_resource(monsters);
+        world.add_resource(vaults);
+
+        let player = load_entities(&mut world, save.entities).ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, "the save has no player")
+        })?;
+        world.write_resource::<Turns>().0 = save.turns;
+        world.write_resource::<Depth>().0 = save.depth;
+
+        let map = map::Map::load(save.map);
+        let floors = save
+            .floors
+            .into_iter()
+            .map(|floor| floor.map(map::Map::load))
+            .collect();
+        let rng = tcod::random::Rng::new_with_seed(tcod::random::Algo::CMWC, save.seed);
+
+        let mut game = Game::from_world(world, dispatcher, player, map, rng, floors, keymap);
+        game.player_turns = save.player_turns;
+        Ok(game)
+    }
+
+    fn from_world(
+        mut world: World,
+        dispatcher: Dispatcher<'a, 'b>,
+        player: Entity,
+        map: map::Map,
+        rng: tcod::random::Rng,
+        floors: Vec<Option<map::Map>>,
+        keymap: keymap::KeyMap,
+    ) -> Game<'a, 'b> {
+        let messages = Arc::new(Mutex::new(messages::MessageLog::new()));
         world
             .write_resource::<observer::Dispatcher>()
-            .register_listener(logger);
+            .register_listener(messages.clone());
+        messages.lock().unwrap().add(
+            "Welcome stranger! Prepare to perish in the Tombs of the Ancient Kings.",
+            tcod::colors::RED,
+        );
         world.add_resource(Player(player.clone()));
         world.add_resource(map);
         world.add_resource(Rng(Arc::new(Mutex::new(rng))));
+        update_lighting(&world);
+        draw(&world);
 
         Game {
             world: world,
             dispatcher: dispatcher,
             player: player,
-            action: PlayerAction::TookTurn,
+            action: PlayerAction::DidntTakeTurn,
+            player_turns: 0,
+            floors: floors,
+            messages: messages,
+            inventory_menu: None,
+            level_up_menu: None,
+            equipment_menu: None,
+            pending_throw: None,
+            targeting: None,
+            game_over: None,
+            restart: false,
+            restart_request: None,
+            save_path: Some(save::SAVE_FILE),
+            keymap: keymap,
+            mouse: (-1, -1),
         }
     }
 
-    fn handle_key(&mut self, key: tcod::input::Key) -> Transition {
-        use tcod::input::Key;
-        use tcod::input::KeyCode;
-        let mut event_storage = self.world.write_storage::<MoveEvent>();
-        let (dx, dy) = match key {
-            Key {
-                code: KeyCode::Up, ..
-            } => (0, -1),
-            Key {
-                code: KeyCode::Down,
-                ..
-            } => (0, 1),
-            Key {
-                code: KeyCode::Left,
-                ..
-            } => (-1, 0),
-            Key {
-                code: KeyCode::Right,
-                ..
-            } => (1, 0),
-            Key {
-                code: KeyCode::Escape,
-                ..
-            } => return Transition::Pop,
-            _ => (0, 0),
+    pub fn register_listener(&mut self, listener: Arc<Mutex<observer::Listener + Send>>) {
+        self.world
+            .write_resource::<observer::Dispatcher>()
+            .register_listener(listener);
+    }
+
+    /// Adds what the dungeon generation had to say to the message log
+    fn log_notes(&self, notes: Vec<String>) {
+        let mut messages = self.messages.lock().unwrap();
+        for note in notes {
+            messages.add(note, tcod::colors::LIGHT_GREY);
+        }
+    }
+
+    fn is_dead(&self) -> bool {
+        !self.world.read_storage::<Properties>().get(self.player).map_or(false, |p| p.alive)
+    }
+
+    fn open_game_over(&mut self) -> Transition {
+        let (kills, level) = stats_of(&self.world, self.player).map_or((0, 1), |stats| (stats.kills, stats.level));
+        let summary = game_over::Summary {
+            cause: death_of(&self.world, self.player).unwrap_or_else(|| "Died".into()),
+            turns: self.player_turns,
+            kills: kills,
+            level: level,
+            depth: self.world.read_resource::<Depth>().0,
+        };
+        let choice = game_over::choice();
+        self.game_over = Some(choice.clone());
+        self.action = PlayerAction::DidntTakeTurn;
+        Transition::Push(Box::new(game_over::GameOver::new(&summary, choice)))
+    }
+
+    /// Where to ask for a new game when the player wants one after dying, the game is popped right after.
+    /// Without it, asking for a new game just ends this one.
+    pub fn set_restart_request(&mut self, request: Rc<Cell<bool>>) {
+        self.restart_request = Some(request);
+    }
+
+    /// Keeps the game from being written to disk when it stops, for replays
+    pub fn disable_saving(&mut self) {
+        self.save_path = None;
+    }
+
+    /// Writes the whole game to disk
+    pub fn save(&self, path: &str) -> std::io::Result<()> {
+        // reseed the rng from itself: the loaded game will then draw exactly what this one would have
+        let seed = {
+            let rng = self.world.read_resource::<Rng>();
+            let mut rng = rng.0.lock().unwrap();
+            let seed = rng.get_int(0, std::i32::MAX) as u32;
+            *rng = tcod::random::Rng::new_with_seed(tcod::random::Algo::CMWC, seed);
+            seed
         };
-        self.action = if dx == 0 && dy == 0 {
-            PlayerAction::DidntTakeTurn
-        } else {
-            event_storage
-                .insert(self.player, MoveEvent(dx, dy))
-                .unwrap();
-            PlayerAction::TookTurn
+        let save = save::SaveGame {
+            seed: seed,
+            turns: self.world.read_resource::<Turns>().0,
+            player_turns: self.player_turns,
+            depth: self.world.read_resource::<Depth>().0,
+            map: self.world.read_resource::<map::Map>().save(),
+            floors: self
+                .floors
+                .iter()
+                .map(|floor| floor.as_ref().map(|map| map.save()))
+                .collect(),
+            entities: save_entities(&self.world, self.player),
         };
-        Transition::None
+        save::save_game(path, &save)
+    }
+
+    fn open_inventory(&mut self, action: InventoryAction) -> Transition {
+        let items = inventory_of(&self.world, self.player);
+        if items.is_empty() {
+            self.messages
+                .lock()
+                .unwrap()
+                .add("Your inventory is empty.", tcod::colors::LIGHT_GREY);
+            return Transition::None;
+        }
+        let title = match action {
+            InventoryAction::Use => "Press the key next to an item to use it, or Escape to cancel.",
+            InventoryAction::Drop => "Press the key next to an item to drop it, or Escape to cancel.",
+            InventoryAction::Throw => "Press the key next to an item to throw it, or Escape to cancel.",
+        };
+        let choice = menu::choice();
+        let (entities, names): (Vec<Entity>, Vec<String>) = items.into_iter().unzip();
+        self.inventory_menu = Some((action, entities, choice.clone()));
+        Transition::Push(Box::new(menu::Menu::new(title, names, INVENTORY_WIDTH, choice)))
+    }
+
+    fn open_equipment(&mut self) -> Transition {
+        let entries = equipment_of(&self.world, self.player);
+        if entries.is_empty() {
+            self.messages
+                .lock()
+                .unwrap()
+                .add("You carry nothing you could equip.", tcod::colors::LIGHT_GREY);
+            return Transition::None;
+        }
+        let choice = menu::choice();
+        self.equipment_menu = Some((entries.iter().map(|entry| entry.item).collect(), choice.clone()));
+        Transition::Push(Box::new(equipment::menu(&entries, choice)))
+    }
+
+    /// Lets the player pick a visible tile within `range`
+    fn open_targeting(&mut self, action: TargetAction, range: i32) -> Transition {
+        let (x, y) = match position_of(&self.world, self.player) {
+            Some(pos) => pos,
+            None => return Transition::None,
+        };
+        let tiles = {
+            let map = self.world.read_resource::<map::Map>();
+            let mut tiles = Vec::new();
+            for ty in (y - range)..(y + range + 1) {
+                for tx in (x - range)..(x + range + 1) {
+                    let in_map = tx >= 0 && ty >= 0 && tx < map.width() && ty < map.height();
+                    let in_range = (tx - x).pow(2) + (ty - y).pow(2) <= range.pow(2);
+                    if in_map && in_range && map.is_in_fov(tx, ty) {
+                        tiles.push((tx, ty));
+                    }
+                }
+            }
+            tiles
+        };
+        let target = targeting::target();
+        self.targeting = Some((action, target.clone()));
+        Transition::Push(Box::new(targeting::Targeting::new(
+            (x, y),
+            tiles,
+            visible_targets(&self.world, (x, y)),
+            target,
+        )))
+    }
+
+    fn open_level_up(&mut self) -> Transition {
+        let stats = match stats_of(&self.world, self.player) {
+            Some(stats) => stats,
+            None => return Transition::None,
+        };
+        self.messages.lock().unwrap().add(
+            format!(
+                "Your battle skills grow stronger! You reached level {}!",
+                stats.level + 1
+            ),
+            tcod::colors::YELLOW,
+        );
+        let choice = menu::choice();
+        self.level_up_menu = Some(choice.clone());
+        self.action = PlayerAction::DidntTakeTurn;
+        Transition::Push(Box::new(
+            menu::Menu::new(
+                "Level up! Choose a stat to raise:",
+                vec![
+                    format!("Constitution (+20 HP, from {})", stats.max_hp),
+                    format!("Strength (+1 attack, from {})", stats.base_attack),
+                    format!("Agility (+1 defense, from {})", stats.base_defense),
+                ],
+                LEVEL_SCREEN_WIDTH,
+                choice,
+            ).forced(),
+        ))
+    }
+
+    /// Takes the stairs under the player, if they go in the wanted direction
+    fn take_stairs(&mut self, direction: map::Stairs) -> PlayerAction {
+        let (x, y) = match position_of(&self.world, self.player) {
+            Some(pos) => pos,
+            None => return PlayerAction::DidntTakeTurn,
+        };
+        if self.world.read_resource::<map::Map>().stairs_at(x, y) != Some(direction) {
+            return PlayerAction::DidntTakeTurn;
+        }
+        let depth = self.world.read_resource::<Depth>().0;
+        let new_depth = match direction {
+            map::Stairs::Up => depth - 1,
+            map::Stairs::Down => depth + 1,
+        };
+        if new_depth < 1 {
+            self.world.write_resource::<observer::Dispatcher>().dispatch(observer::Event::Log(
+                self.player,
+                "The way out of the dungeon is sealed".into(),
+                tcod::colors::LIGHT_GREY,
+            ));
+            return PlayerAction::DidntTakeTurn;
+        }
+
+        leave_floor(&mut self.world, depth, self.player);
+        if self.floors.len() < new_depth as usize {
+            self.floors.push(None);
+        }
+
+        let (width, height) = {
+            let map = self.world.read_resource::<map::Map>();
+            (map.width(), map.height())
+        };
+        let new_map = match self.floors[(new_depth - 1) as usize].take() {
+            Some(map) => {
+                enter_floor(&mut self.world, new_depth);
+                map
+            }
+            None => {
+                let rng = self.world.read_resource::<Rng>().0.clone();
+                let mut rng = rng.lock().unwrap();
+                let (map, _, notes) = generate_floor(&mut self.world, &mut *rng, width, height, new_depth);
+                self.log_notes(notes);
+                map
+            }
+        };
+        // arrive on the stairs leading back to where we came from
+        let arrival = match direction {
+            map::Stairs::Up => map::Stairs::Down,
+            map::Stairs::Down => map::Stairs::Up,
+        };
+        let (x, y) = new_map.stairs(arrival).unwrap_or((x, y));
+
+        let old_map = std::mem::replace(&mut *self.world.write_resource::<map::Map>(), new_map);
+        self.floors[(depth - 1) as usize] = Some(old_map);
+        self.world.write_resource::<Depth>().0 = new_depth;
+
+        place_entity(&mut self.world, self.player, x, y);
+        update_lighting(&self.world);
+        self.world.read_resource::<DisplayConsole>().get().clear();
+        self.world.maintain();
+        PlayerAction::TookTurn
+    }
+
+    fn handle_key(&mut self, key: tcod::input::Key) -> Transition {
+        let action = self.keymap.action(key);
+        // only the final map is left to look at, any key brings the game over screen back
+        if self.is_dead() {
+            return match action {
+                Some(Action::Quit) => Transition::Pop,
+                _ => self.open_game_over(),
+            };
+        }
+        self.action = PlayerAction::DidntTakeTurn;
+        let action = match action {
+            Some(action) => action,
+            None => return Transition::None,
+        };
+        match action {
+            Action::Move(dx, dy) => {
+                self.world
+                    .write_storage::<MoveEvent>()
+                    .insert(self.player, MoveEvent(dx, dy))
+                    .unwrap();
+                self.action = PlayerAction::TookTurn;
+                Transition::None
+            }
+            Action::Wait => {
+                // the turn is spent doing nothing
+                self.action = PlayerAction::TookTurn;
+                Transition::None
+            }
+            Action::PickUp => {
+                self.world
+                    .write_storage::<PickUpEvent>()
+                    .insert(self.player, PickUpEvent)
+                    .unwrap();
+                self.action = PlayerAction::TookTurn;
+                Transition::None
+            }
+            Action::Inventory => self.open_inventory(InventoryAction::Use),
+            Action::Drop => self.open_inventory(InventoryAction::Drop),
+            Action::Throw => self.open_inventory(InventoryAction::Throw),
+            Action::Fire => match shooting_range_of(&self.world, self.player) {
+                Some(range) => self.open_targeting(TargetAction::Shoot, range),
+                None => {
+                    self.messages
+                        .lock()
+                        .unwrap()
+                        .add("You have no ranged weapon equipped.", tcod::colors::LIGHT_GREY);
+                    Transition::None
+                }
+            },
+            Action::Equipment => self.open_equipment(),
+            Action::Character => match stats_of(&self.world, self.player) {
+                Some(stats) => Transition::Push(Box::new(character::CharacterSheet::new(&stats))),
+                None => Transition::None,
+            },
+            Action::Messages => Transition::Push(Box::new(messages::MessageHistory::new(
+                self.messages.clone(),
+            ))),
+            Action::Descend => {
+                self.action = self.take_stairs(map::Stairs::Down);
+                Transition::None
+            }
+            Action::Ascend => {
+                self.action = self.take_stairs(map::Stairs::Up);
+                Transition::None
+            }
+            Action::Look => {
+                // anything in view can be looked at
+                let range = {
+                    let map = self.world.read_resource::<map::Map>();
+                    std::cmp::max(map.width(), map.height())
+                };
+                self.open_targeting(TargetAction::Look, range)
+            }
+            Action::Close => {
+                match open_door_near(&self.world, self.player) {
+                    Some((x, y)) => {
+                        self.world
+                            .write_storage::<CloseDoorEvent>()
+                            .insert(self.player, CloseDoorEvent(x, y))
+                            .unwrap();
+                        self.action = PlayerAction::TookTurn;
+                    }
+                    None => self
+                        .messages
+                        .lock()
+                        .unwrap()
+                        .add("There is no open door next to you.", tcod::colors::LIGHT_GREY),
+                }
+                Transition::None
+            }
+            Action::Quit => Transition::Pop,
+        }
     }
 }
 
 impl<'a, 'b> State for Game<'a, 'b> {
-    fn render(&self, screen: &mut tcod::Console) {
+    fn render(&self, screen: &mut Screen) {
         let console = self.world.read_resource::<DisplayConsole>();
-        console.get().print(
-            0,
-            35,
-            format!("turns: {}", self.world.read_resource::<Turns>().0),
-        );
-        tcod::console::blit(&*console.get(), (0, 0), (0, 0), screen, (0, 0), 1.0, 1.0);
+        let panel_y = self.world.read_resource::<map::Map>().height();
+        {
+            let mut con = console.get();
+            let (width, height) = (con.width(), con.height());
+            con.fill(0, panel_y, width, height - panel_y, tcod::colors::BLACK);
+            con.print(
+                0,
+                panel_y,
+                &format!(
+                    "time: {}  depth: {}",
+                    self.world.read_resource::<Turns>().0,
+                    self.world.read_resource::<Depth>().0
+                ),
+                tcod::colors::WHITE,
+            );
+            let names = names_at(&self.world, self.mouse.0, self.mouse.1);
+            if !names.is_empty() {
+                con.print(30, panel_y, &names.join(", "), tcod::colors::LIGHT_GREY);
+            }
+            self.messages.lock().unwrap().render(
+                &mut *con,
+                1,
+                panel_y + 1,
+                width - 2,
+                height - panel_y - 1,
+                0,
+            );
+        }
+        console.get().blit(screen);
     }
 
     fn update(&mut self) -> Transition {
+        if self.restart {
+            self.restart = false;
+            if let Some(ref request) = self.restart_request {
+                request.set(true);
+            }
+            return Transition::Pop;
+        }
+        if let Some(item) = self.pending_throw.take() {
+            return self.open_targeting(TargetAction::Throw(item), THROW_RANGE);
+        }
         if self.action == PlayerAction::DidntTakeTurn {
             return Transition::None;
         }
-        self.world.write_resource::<Turns>().0 += 1;
-        self.dispatcher.dispatch(&mut self.world.res);
-        self.world.maintain();
+        // the main loop keeps updating between events, a turn has to be taken only once
+        self.action = PlayerAction::DidntTakeTurn;
+        self.player_turns += 1;
+        // the player acts on the first tick, then time passes until the player can act again
+        loop {
+            self.world.write_resource::<Turns>().0 += 1;
+            self.dispatcher.dispatch(&mut self.world.res);
+            self.world.maintain();
+            if is_ready(&self.world, self.player) {
+                break;
+            }
+        }
+
+        let turn = self.world.read_resource::<Turns>().0;
+        let hash = state_hash(&self.world);
+        self.world
+            .write_resource::<observer::Dispatcher>()
+            .dispatch(observer::Event::TurnEnded(turn, hash));
+
+        if self.is_dead() {
+            return self.open_game_over();
+        }
+        if can_level_up(&self.world, self.player) {
+            return self.open_level_up();
+        }
         Transition::None
     }
 
+    fn on_resume(&mut self) {
+        if let Some(choice) = self.game_over.take() {
+            // viewing the map is just staying here
+            self.restart = choice.get() == Some(game_over::GameOverChoice::NewGame);
+        }
+        if let Some(choice) = self.level_up_menu.take() {
+            let choice = match choice.get() {
+                Some(0) => LevelUpChoice::Constitution,
+                Some(1) => LevelUpChoice::Strength,
+                _ => LevelUpChoice::Agility,
+            };
+            level_up(&mut self.world, self.player, choice);
+        }
+        if let Some((action, items, choice)) = self.inventory_menu.take() {
+            if let Some(item) = choice.get().map(|index| items[index]) {
+                match action {
+                    InventoryAction::Use => {
+                        self.world
+                            .write_storage::<UseItemEvent>()
+                            .insert(self.player, UseItemEvent(item))
+                            .unwrap();
+                    }
+                    InventoryAction::Drop => {
+                        self.world
+                            .write_storage::<DropEvent>()
+                            .insert(self.player, DropEvent(item))
+                            .unwrap();
+                    }
+                    InventoryAction::Throw => {
+                        // throwing takes a turn only once a target is picked
+                        self.pending_throw = Some(item);
+                        return;
+                    }
+                }
+                self.action = PlayerAction::TookTurn;
+            }
+        }
+        if let Some((action, target)) = self.targeting.take() {
+            if let Some((x, y)) = target.get() {
+                match action {
+                    TargetAction::Shoot => {
+                        self.world
+                            .write_storage::<ShootEvent>()
+                            .insert(self.player, ShootEvent(x, y))
+                            .unwrap();
+                    }
+                    TargetAction::Throw(item) => {
+                        self.world
+                            .write_storage::<ThrowEvent>()
+                            .insert(self.player, ThrowEvent(item, x, y))
+                            .unwrap();
+                    }
+                    TargetAction::Look => {
+                        let names = names_at(&self.world, x, y);
+                        let text = if names.is_empty() {
+                            "You see nothing of interest there.".to_string()
+                        } else {
+                            format!("You see: {}.", names.join(", "))
+                        };
+                        self.messages.lock().unwrap().add(text, tcod::colors::LIGHT_GREY);
+                    }
+                }
+                if action != TargetAction::Look {
+                    self.action = PlayerAction::TookTurn;
+                }
+            }
+        }
+        if let Some((items, choice)) = self.equipment_menu.take() {
+            if let Some(item) = choice.get().map(|index| items[index]) {
+                self.world
+                    .write_storage::<EquipEvent>()
+                    .insert(self.player, EquipEvent(item))
+                    .unwrap();
+                self.action = PlayerAction::TookTurn;
+            }
+        }
+    }
+
+    fn on_stop(&mut self) {
+        if let Some(path) = self.save_path {
+            // death is final, a dead player's game is not kept around
+            let result = if self.is_dead() { save::delete_save(path) } else { self.save(path) };
+            if let Err(e) = result {
+                println!("could not save the game: {}", e);
+            }
+        }
+    }
+
     fn handle_event(&mut self, event: Event) -> Transition {
         match event {
             Event::Key(key) => self.handle_key(key),
+            Event::MouseMove(x, y) => {
+                self.mouse = (x, y);
+                Transition::None
+            }
+            Event::MouseClick(..) => Transition::None,
         }
     }
 }
@@ -0,0 +1,97 @@
+use std::cell::Cell;
+use std::rc::Rc;
+
+use menu;
+use screen::Screen;
+use state_machine::{Event, Layer, State, Transition};
+
+const GAME_OVER_WIDTH: i32 = 40;
+/// How much the final map is darkened behind the screen
+const BACKDROP_DIM: f32 = 0.6;
+
+/// What the player wants once the game is over, quitting is done by the screen itself
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum GameOverChoice {
+    NewGame,
+    ViewMap,
+}
+
+/// Where the game over screen writes the player's choice, read back by the game
+pub type Choice = Rc<Cell<Option<GameOverChoice>>>;
+
+pub fn choice() -> Choice {
+    Rc::new(Cell::new(None))
+}
+
+/// How the run went, for the game over screen
+pub struct Summary {
+    pub cause: String,
+    pub turns: i64,
+    pub kills: i32,
+    pub level: i32,
+    pub depth: i32,
+}
+
+/// Shown when the player dies, offers a new game, a look at the final map or quitting.
+///
+/// The options are a `menu::Menu` with the summary above them, drawn over the dimmed final map.
+pub struct GameOver {
+    menu: menu::Menu,
+    picked: menu::Choice,
+    choice: Choice,
+}
+
+impl GameOver {
+    pub fn new(summary: &Summary, choice: Choice) -> GameOver {
+        choice.set(None);
+        let header = vec![
+            "You died!".to_string(),
+            String::new(),
+            summary.cause.clone(),
+            String::new(),
+            format!("Turns survived: {}", summary.turns),
+            format!("Monsters killed: {}", summary.kills),
+            format!("Level reached: {}", summary.level),
+            format!("Floor reached: {}", summary.depth),
+        ];
+        let options = vec!["New game".to_string(), "View the final map".into(), "Quit".into()];
+        let picked = menu::choice();
+        GameOver {
+            menu: menu::Menu::new("", options, GAME_OVER_WIDTH, picked.clone()).with_header(header),
+            picked: picked,
+            choice: choice,
+        }
+    }
+}
+
+impl State for GameOver {
+    fn render(&self, screen: &mut Screen) {
+        self.menu.render(screen);
+    }
+
+    fn layer(&self) -> Layer {
+        Layer::Dimmed(BACKDROP_DIM)
+    }
+
+    fn update(&mut self) -> Transition {
+        Transition::None
+    }
+
+    fn handle_event(&mut self, event: Event) -> Transition {
+        match self.menu.handle_event(event) {
+            Transition::Pop => match self.picked.get() {
+                Some(0) => {
+                    self.choice.set(Some(GameOverChoice::NewGame));
+                    Transition::Pop
+                }
+                Some(2) => Transition::Quit,
+                // escape leaves the screen to look at the map too
+                _ => {
+                    self.choice.set(Some(GameOverChoice::ViewMap));
+                    Transition::Pop
+                }
+            },
+            transition => transition,
+        }
+    }
+}
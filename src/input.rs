@@ -0,0 +1,86 @@
+use tcod::input::KeyCode;
+
+/// Every tcod key code with a stable name, used wherever keys are written to a file
+const KEY_CODES: &[(KeyCode, &str)] = &[
+    (KeyCode::NoKey, "NoKey"),
+    (KeyCode::Escape, "Escape"),
+    (KeyCode::Backspace, "Backspace"),
+    (KeyCode::Tab, "Tab"),
+    (KeyCode::Enter, "Enter"),
+    (KeyCode::Shift, "Shift"),
+    (KeyCode::Control, "Control"),
+    (KeyCode::Alt, "Alt"),
+    (KeyCode::Pause, "Pause"),
+    (KeyCode::CapsLock, "CapsLock"),
+    (KeyCode::PageUp, "PageUp"),
+    (KeyCode::PageDown, "PageDown"),
+    (KeyCode::End, "End"),
+    (KeyCode::Home, "Home"),
+    (KeyCode::Up, "Up"),
+    (KeyCode::Left, "Left"),
+    (KeyCode::Right, "Right"),
+    (KeyCode::Down, "Down"),
+    (KeyCode::PrintScreen, "PrintScreen"),
+    (KeyCode::Insert, "Insert"),
+    (KeyCode::Delete, "Delete"),
+    (KeyCode::LeftWin, "LeftWin"),
+    (KeyCode::RightWin, "RightWin"),
+    (KeyCode::Apps, "Apps"),
+    (KeyCode::Number0, "Number0"),
+    (KeyCode::Number1, "Number1"),
+    (KeyCode::Number2, "Number2"),
+    (KeyCode::Number3, "Number3"),
+    (KeyCode::Number4, "Number4"),
+    (KeyCode::Number5, "Number5"),
+    (KeyCode::Number6, "Number6"),
+    (KeyCode::Number7, "Number7"),
+    (KeyCode::Number8, "Number8"),
+    (KeyCode::Number9, "Number9"),
+    (KeyCode::NumPad0, "NumPad0"),
+    (KeyCode::NumPad1, "NumPad1"),
+    (KeyCode::NumPad2, "NumPad2"),
+    (KeyCode::NumPad3, "NumPad3"),
+    (KeyCode::NumPad4, "NumPad4"),
+    (KeyCode::NumPad5, "NumPad5"),
+    (KeyCode::NumPad6, "NumPad6"),
+    (KeyCode::NumPad7, "NumPad7"),
+    (KeyCode::NumPad8, "NumPad8"),
+    (KeyCode::NumPad9, "NumPad9"),
+    (KeyCode::NumPadAdd, "NumPadAdd"),
+    (KeyCode::NumPadSubtract, "NumPadSubtract"),
+    (KeyCode::NumPadDivide, "NumPadDivide"),
+    (KeyCode::NumPadMultiply, "NumPadMultiply"),
+    (KeyCode::NumPadDecimal, "NumPadDecimal"),
+    (KeyCode::NumPadEnter, "NumPadEnter"),
+    (KeyCode::F1, "F1"),
+    (KeyCode::F2, "F2"),
+    (KeyCode::F3, "F3"),
+    (KeyCode::F4, "F4"),
+    (KeyCode::F5, "F5"),
+    (KeyCode::F6, "F6"),
+    (KeyCode::F7, "F7"),
+    (KeyCode::F8, "F8"),
+    (KeyCode::F9, "F9"),
+    (KeyCode::F10, "F10"),
+    (KeyCode::F11, "F11"),
+    (KeyCode::F12, "F12"),
+    (KeyCode::NumLock, "NumLock"),
+    (KeyCode::ScrollLock, "ScrollLock"),
+    (KeyCode::Spacebar, "Spacebar"),
+    (KeyCode::Char, "Char"),
+    (KeyCode::Text, "Text"),
+];
+
+pub fn key_code_name(code: KeyCode) -> &'static str {
+    KEY_CODES
+        .iter()
+        .find(|&&(c, _)| c == code)
+        .map_or("NoKey", |&(_, name)| name)
+}
+
+pub fn key_code_from_name(name: &str) -> Option<KeyCode> {
+    KEY_CODES
+        .iter()
+        .find(|&&(_, n)| n == name)
+        .map(|&(code, _)| code)
+}
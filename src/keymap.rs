@@ -0,0 +1,299 @@
+use std;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::BufReader;
+
+use serde_json;
+use tcod::input::{Key, KeyCode};
+
+use input;
+
+pub const KEYMAP_FILE: &str = "data/keys.json";
+
+/// Everything the player can ask for on the game screen
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Action {
+    Move(i32, i32),
+    Wait,
+    PickUp,
+    Inventory,
+    Drop,
+    Throw,
+    Fire,
+    Equipment,
+    Character,
+    Messages,
+    Descend,
+    Ascend,
+    Look,
+    /// closes an open door next to the player
+    Close,
+    /// back to the title menu
+    Quit,
+}
+
+/// Every action with its name in the keymap file, in the order conflicting keys are resolved
+const ACTIONS: &[(Action, &str)] = &[
+    (Action::Move(0, -1), "move_north"),
+    (Action::Move(0, 1), "move_south"),
+    (Action::Move(-1, 0), "move_west"),
+    (Action::Move(1, 0), "move_east"),
+    (Action::Move(-1, -1), "move_north_west"),
+    (Action::Move(1, -1), "move_north_east"),
+    (Action::Move(-1, 1), "move_south_west"),
+    (Action::Move(1, 1), "move_south_east"),
+    (Action::Wait, "wait"),
+    (Action::PickUp, "pick_up"),
+    (Action::Inventory, "inventory"),
+    (Action::Drop, "drop"),
+    (Action::Throw, "throw"),
+    (Action::Fire, "fire"),
+    (Action::Equipment, "equipment"),
+    (Action::Character, "character"),
+    (Action::Messages, "messages"),
+    (Action::Descend, "descend"),
+    (Action::Ascend, "ascend"),
+    (Action::Look, "look"),
+    (Action::Close, "close"),
+    (Action::Quit, "quit"),
+];
+
+fn action_name(action: Action) -> &'static str {
+    ACTIONS.iter().find(|&&(a, _)| a == action).map_or("", |&(_, name)| name)
+}
+
+/// Keys of every action when the keymap file does not say otherwise: arrows, numpad and vi-keys
+const DEFAULTS: &[(&str, &[&str])] = &[
+    ("move_north", &["Up", "NumPad8", "k"]),
+    ("move_south", &["Down", "NumPad2", "j"]),
+    ("move_west", &["Left", "NumPad4", "h"]),
+    ("move_east", &["Right", "NumPad6", "l"]),
+    ("move_north_west", &["Home", "NumPad7", "y"]),
+    ("move_north_east", &["PageUp", "NumPad9", "u"]),
+    ("move_south_west", &["End", "NumPad1", "b"]),
+    ("move_south_east", &["PageDown", "NumPad3", "n"]),
+    ("wait", &["NumPad5", "."]),
+    ("pick_up", &["g", ","]),
+    ("inventory", &["i"]),
+    ("drop", &["d"]),
+    ("throw", &["t"]),
+    ("fire", &["f"]),
+    ("equipment", &["e"]),
+    ("character", &["c"]),
+    ("messages", &["m"]),
+    ("descend", &[">"]),
+    ("ascend", &["<"]),
+    ("look", &["x"]),
+    ("close", &["C"]),
+    ("quit", &["Escape"]),
+];
+
+/// A key as written in the keymap file: a single character, or a key code name like `NumPad8`
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Binding {
+    Code(KeyCode),
+    Char(char),
+}
+
+impl Binding {
+    fn parse(name: &str) -> Option<Binding> {
+        let mut chars = name.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => Some(Binding::Char(c)),
+            _ => input::key_code_from_name(name)
+                // these stand for any printable key, single characters are used instead
+                .filter(|&code| code != KeyCode::Char && code != KeyCode::Text && code != KeyCode::NoKey)
+                .map(Binding::Code),
+        }
+    }
+
+    fn name(&self) -> String {
+        match *self {
+            Binding::Code(code) => input::key_code_name(code).into(),
+            Binding::Char(c) => c.to_string(),
+        }
+    }
+
+    fn matches(&self, key: Key) -> bool {
+        match *self {
+            Binding::Code(code) => key.code == code,
+            Binding::Char(c) => key.printable == c,
+        }
+    }
+}
+
+/// A keymap file that could not be read or that holds invalid bindings
+#[derive(Debug)]
+pub struct KeyMapError {
+    pub file: String,
+    pub message: String,
+}
+
+impl std::fmt::Display for KeyMapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}: {}", self.file, self.message)
+    }
+}
+
+/// The keys bound to every action.
+///
+/// The file maps action names to lists of keys, like `"wait": ["NumPad5", "."]`. An action it lists
+/// gets exactly these keys, the others keep their defaults.
+#[derive(Clone)]
+pub struct KeyMap {
+    /// in the order of `ACTIONS`
+    bindings: Vec<(Action, Vec<Binding>)>,
+}
+
+impl KeyMap {
+    /// Reads the keymap file, a missing file leaves every action on its default keys
+    pub fn load(path: &str) -> Result<KeyMap, KeyMapError> {
+        let error = |message: String| KeyMapError {
+            file: path.into(),
+            message: message,
+        };
+
+        let mut names: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        if std::path::Path::new(path).is_file() {
+            let file = File::open(path).map_err(|e| error(e.to_string()))?;
+            names = serde_json::from_reader(BufReader::new(file)).map_err(|e| error(e.to_string()))?;
+        }
+        KeyMap::from_names(&names).map_err(error)
+    }
+
+    /// Builds the keymap from action names and key names, as found in the keymap file
+    pub fn from_names(names: &BTreeMap<String, Vec<String>>) -> Result<KeyMap, String> {
+        for action in names.keys() {
+            if !ACTIONS.iter().any(|&(_, name)| name == action) {
+                return Err(format!("unknown action `{}`", action));
+            }
+        }
+
+        let mut bindings = Vec::new();
+        for &(action, name) in ACTIONS.iter() {
+            let keys: Vec<String> = match names.get(name) {
+                Some(keys) => keys.clone(),
+                None => DEFAULTS
+                    .iter()
+                    .find(|&&(n, _)| n == name)
+                    .map_or(Vec::new(), |&(_, keys)| keys.iter().map(|&k| k.into()).collect()),
+            };
+            let mut parsed = Vec::new();
+            for key in keys {
+                match Binding::parse(&key) {
+                    Some(binding) => parsed.push(binding),
+                    None => return Err(format!("unknown key `{}` for `{}`", key, name)),
+                }
+            }
+            bindings.push((action, parsed));
+        }
+
+        Ok(KeyMap { bindings: bindings })
+    }
+
+    /// Every action with the names of its keys, what `from_names` reads back
+    pub fn names(&self) -> BTreeMap<String, Vec<String>> {
+        self.bindings
+            .iter()
+            .map(|&(action, ref keys)| (action_name(action).into(), keys.iter().map(|key| key.name()).collect()))
+            .collect()
+    }
+
+    /// The action bound to `key`, the first one listed wins if several are
+    pub fn action(&self, key: Key) -> Option<Action> {
+        self.bindings
+            .iter()
+            .find(|&&(_, ref keys)| keys.iter().any(|binding| binding.matches(key)))
+            .map(|&(action, _)| action)
+    }
+
+    /// Keys bound to more than one action, one line per key
+    pub fn conflicts(&self) -> Vec<String> {
+        let mut conflicts = Vec::new();
+        let mut seen: Vec<Binding> = Vec::new();
+        for &(_, ref keys) in self.bindings.iter() {
+            for &binding in keys.iter() {
+                if seen.contains(&binding) {
+                    continue;
+                }
+                seen.push(binding);
+                let actions: Vec<&str> = self
+                    .bindings
+                    .iter()
+                    .filter(|&&(_, ref keys)| keys.contains(&binding))
+                    .map(|&(action, _)| action_name(action))
+                    .collect();
+                if actions.len() > 1 {
+                    conflicts.push(format!(
+                        "`{}` is bound to {}, only `{}` will be used",
+                        binding.name(),
+                        actions.join(", "),
+                        actions[0]
+                    ));
+                }
+            }
+        }
+        conflicts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn char_key(c: char) -> Key {
+        Key {
+            code: KeyCode::Char,
+            printable: c,
+            pressed: true,
+            ..Default::default()
+        }
+    }
+
+    fn names(pairs: &[(&str, &[&str])]) -> BTreeMap<String, Vec<String>> {
+        pairs
+            .iter()
+            .map(|&(action, keys)| (action.to_string(), keys.iter().map(|&k| k.to_string()).collect()))
+            .collect()
+    }
+
+    #[test]
+    fn defaults_have_no_conflicts() {
+        let keymap = KeyMap::from_names(&BTreeMap::new()).unwrap();
+        assert!(keymap.conflicts().is_empty());
+        assert_eq!(keymap.action(char_key('y')), Some(Action::Move(-1, -1)));
+        let numpad = Key { code: KeyCode::NumPad5, ..Default::default() };
+        assert_eq!(keymap.action(numpad), Some(Action::Wait));
+    }
+
+    #[test]
+    fn listed_actions_replace_their_defaults_only() {
+        let keymap = KeyMap::from_names(&names(&[("wait", &["s"])])).unwrap();
+        assert_eq!(keymap.action(char_key('s')), Some(Action::Wait));
+        assert_eq!(keymap.action(char_key('.')), None);
+        assert_eq!(keymap.action(char_key('g')), Some(Action::PickUp));
+    }
+
+    #[test]
+    fn conflicts_name_both_actions_and_the_first_wins() {
+        let keymap = KeyMap::from_names(&names(&[("look", &["k"])])).unwrap();
+        let conflicts = keymap.conflicts();
+        assert_eq!(conflicts.len(), 1);
+        assert!(conflicts[0].contains("move_north") && conflicts[0].contains("look"));
+        assert_eq!(keymap.action(char_key('k')), Some(Action::Move(0, -1)));
+    }
+
+    #[test]
+    fn unknown_names_are_refused() {
+        assert!(KeyMap::from_names(&names(&[("dance", &["d"])])).is_err());
+        assert!(KeyMap::from_names(&names(&[("wait", &["Nope"])])).is_err());
+    }
+
+    #[test]
+    fn names_read_back_to_the_same_keymap() {
+        let keymap = KeyMap::from_names(&names(&[("wait", &["s", "NumPad5"])])).unwrap();
+        let again = KeyMap::from_names(&keymap.names()).unwrap();
+        assert_eq!(again.names(), keymap.names());
+        assert_eq!(again.action(char_key('s')), Some(Action::Wait));
+    }
+}
@@ -3,36 +3,233 @@ extern crate specs;
 #[macro_use]
 extern crate specs_derive;
 extern crate rand;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
 
 use tcod::console::{FontType, FontLayout, Root};
+use tcod::input::{self as tcod_input, Mouse};
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
 
 mod state_machine;
+mod character;
 mod components;
+mod equipment;
 mod game;
+mod game_over;
+mod input;
+mod keymap;
 mod map;
+mod mapgen;
+mod menu;
+mod messages;
+mod monsters;
 mod rect;
 mod observer;
-use state_machine::Event;
+mod pathfinding;
+mod replay;
+mod save;
+mod screen;
+mod serde_color;
+mod targeting;
+mod title;
+mod vaults;
+use state_machine::{Event, MouseButton};
 
 const SCREEN_WIDTH: i32 = 80;
 const SCREEN_HEIGHT: i32 = 50;
+const LIMIT_FPS: i32 = 20;
+
+/// Value following `name` on the command line
+fn arg_value(args: &[String], name: &str) -> Option<String> {
+    args.iter()
+        .position(|a| a == name)
+        .and_then(|i| args.get(i + 1))
+        .cloned()
+}
+
+/// Turns a tcod mouse event into a state machine one, moves only count when the cursor changes cell
+fn mouse_event(mouse: Mouse, last_cell: &mut (i32, i32)) -> Option<Event> {
+    let (x, y) = (mouse.cx as i32, mouse.cy as i32);
+    if mouse.lbutton_pressed {
+        Some(Event::MouseClick(x, y, MouseButton::Left))
+    } else if mouse.rbutton_pressed {
+        Some(Event::MouseClick(x, y, MouseButton::Right))
+    } else if (x, y) != *last_cell {
+        *last_cell = (x, y);
+        Some(Event::MouseMove(x, y))
+    } else {
+        None
+    }
+}
+
+/// Plays a recorded game without a window and checks it ends up in the recorded states
+fn run_replay(path: &str) -> i32 {
+    let replay = match replay::Replay::load(path) {
+        Ok(replay) => replay,
+        Err(e) => {
+            println!("could not load {}: {}", path, e);
+            return 2;
+        }
+    };
+
+    let monsters = match monsters::MonsterBook::load(monsters::MONSTERS_FILE) {
+        Ok(monsters) => monsters,
+        Err(e) => {
+            println!("{}", e);
+            return 2;
+        }
+    };
+
+    let vaults = match vaults::VaultBook::load(vaults::VAULTS_FILE) {
+        Ok(vaults) => vaults,
+        Err(e) => {
+            println!("{}", e);
+            return 2;
+        }
+    };
+
+    // the keys of the recording, not the ones of the keymap file as it is now
+    let keymap = match keymap::KeyMap::from_names(&replay.keys) {
+        Ok(keymap) => keymap,
+        Err(e) => {
+            println!("{}: {}", path, e);
+            return 2;
+        }
+    };
+
+    let mut game = game::Game::new(replay.seed, monsters, vaults, keymap, SCREEN_WIDTH, SCREEN_HEIGHT);
+    game.disable_saving();
+    let verifier = Arc::new(Mutex::new(replay::Verifier::new(replay.turns.clone())));
+    game.register_listener(verifier.clone());
+
+    let mut machine = state_machine::StateMachine::new(Box::new(game));
+    let mut screen = screen::Grid::new(SCREEN_WIDTH, SCREEN_HEIGHT);
+
+    machine.start();
+
+    // same order as the main loop, so every turn sees the same events as when it was recorded
+    for recorded in replay.events.iter() {
+        if !machine.is_running() {
+            break;
+        }
+        machine.update();
+        machine.render(&mut screen);
+        match recorded.event() {
+            Some(event) => {
+                verifier.lock().unwrap().event_handled();
+                machine.handle_event(event);
+            }
+            None => {
+                println!("cannot replay event {:?}", recorded);
+                return 2;
+            }
+        }
+    }
+    // the main loop keeps updating after the last event, so its turn gets played too
+    if machine.is_running() {
+        machine.update();
+    }
+    machine.stop();
+
+    let verifier = verifier.lock().unwrap();
+    if let Some(divergence) = verifier.divergence() {
+        println!("{}", divergence);
+        1
+    } else if verifier.missing_turns() > 0 {
+        println!("replay ended {} turns before the recording", verifier.missing_turns());
+        1
+    } else {
+        println!("replay of {} events matches the recording", replay.events.len());
+        0
+    }
+}
 
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
+
+    if let Some(path) = arg_value(&args, "--replay") {
+        std::process::exit(run_replay(&path));
+    }
+
+    let seed = arg_value(&args, "--seed").and_then(|s| s.parse().ok());
+
+    let monsters = match monsters::MonsterBook::load(monsters::MONSTERS_FILE) {
+        Ok(monsters) => monsters,
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let vaults = match vaults::VaultBook::load(vaults::VAULTS_FILE) {
+        Ok(vaults) => vaults,
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let keymap = match keymap::KeyMap::load(keymap::KEYMAP_FILE) {
+        Ok(keymap) => keymap,
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
     let mut root = Root::initializer()
         .font("arial10x10.png", FontLayout::Tcod)
         .font_type(FontType::Greyscale)
         .size(SCREEN_WIDTH, SCREEN_HEIGHT)
         .title("Nameless")
         .init();
-    
-    let mut machine = state_machine::StateMachine::new(Box::new(game::Game::new(42, SCREEN_WIDTH, SCREEN_HEIGHT)));
+    tcod::system::set_fps(LIMIT_FPS);
+
+    let recording: title::Recording = Rc::new(RefCell::new(None));
+    let settings = title::Settings {
+        record_replays: true,
+        frame_rate: LIMIT_FPS,
+    };
+    let conflicts = keymap.conflicts();
+    let mut menu = title::TitleMenu::new(
+        monsters,
+        vaults,
+        keymap,
+        SCREEN_WIDTH,
+        SCREEN_HEIGHT,
+        seed,
+        recording.clone(),
+        settings,
+    );
+    for conflict in conflicts {
+        menu.warn(format!("{}: {}", keymap::KEYMAP_FILE, conflict));
+    }
+    let mut machine = state_machine::StateMachine::new(Box::new(menu));
 
     machine.start();
 
+    let mut mouse_cell = (-1, -1);
     while !root.window_closed() && machine.is_running() {
         machine.update();
         machine.render(&mut root);
         root.flush();
-        machine.handle_event(Event::Key(root.wait_for_keypress(true)));
+        let event = match tcod_input::check_for_event(tcod_input::KEY_PRESS | tcod_input::MOUSE) {
+            Some((_, tcod_input::Event::Key(key))) => Some(Event::Key(key)),
+            Some((_, tcod_input::Event::Mouse(mouse))) => mouse_event(mouse, &mut mouse_cell),
+            None => None,
+        };
+        if let Some(event) = event {
+            // only the events of a recorded game, the title menu creates the recorder
+            if let Some(ref recorder) = *recording.borrow() {
+                recorder.lock().unwrap().record(&event);
+            }
+            machine.handle_event(event);
+        }
     }
+    // closing the window still goes through on_stop, so the game gets saved
+    machine.stop();
+    title::stop_recording(&recording);
 }
@@ -1,4 +1,8 @@
+use components::LightSource;
+use mapgen;
+use mapgen::{MapGenerator, SpawnRegion};
 use rect;
+use screen::Screen;
 use std;
 use tcod;
 
@@ -19,43 +23,171 @@ const COLOR_LIGHT_GROUND: tcod::Color = tcod::Color {
     b: 50,
 };
 
-const ROOM_MAX_SIZE: i32 = 10;
-const ROOM_MIN_SIZE: i32 = 6;
-const MAX_ROOMS: i32 = 30;
+const COLOR_DOOR: tcod::Color = tcod::Color {
+    r: 140,
+    g: 90,
+    b: 40,
+};
+const COLOR_LOCKED_DOOR: tcod::Color = tcod::Color {
+    r: 200,
+    g: 200,
+    b: 200,
+};
+const COLOR_TRAP: tcod::Color = tcod::Color { r: 200, g: 0, b: 0 };
+const COLOR_RUBBLE: tcod::Color = tcod::Color {
+    r: 120,
+    g: 120,
+    b: 120,
+};
 
 const FOV_ALGO: tcod::map::FovAlgorithm = tcod::map::FovAlgorithm::Basic;
 const FOV_LIGHT_WALLS: bool = true;
-const TORCH_RADIUS: i32 = 10;
+/// Light under this, summing the three channels, is too dim to see by
+const MIN_LIGHT: i32 = 40;
+
+/// Adds `color` at the given intensity to the light already on a tile, each channel saturates
+fn add_light(light: tcod::Color, color: tcod::Color, intensity: f32) -> tcod::Color {
+    let add = |channel: u8, extra: u8| channel.saturating_add((extra as f32 * intensity) as u8);
+    tcod::Color {
+        r: add(light.r, color.r),
+        g: add(light.g, color.g),
+        b: add(light.b, color.b),
+    }
+}
+
+/// The lit color of a tile under `light`, never darker than how it is remembered
+fn tint(dark: tcod::Color, lit: tcod::Color, light: tcod::Color) -> tcod::Color {
+    let mix = |dark: u8, lit: u8, light: u8| std::cmp::max(dark, (lit as u32 * light as u32 / 255) as u8);
+    tcod::Color {
+        r: mix(dark.r, lit.r, light.r),
+        g: mix(dark.g, lit.g, light.g),
+        b: mix(dark.b, lit.b, light.b),
+    }
+}
+
+/// Tiles crossed by a straight line from `from` to `to`, `from` excluded
+pub fn line(from: (i32, i32), to: (i32, i32)) -> Vec<(i32, i32)> {
+    let (dx, dy) = ((to.0 - from.0).abs(), -(to.1 - from.1).abs());
+    let (sx, sy) = ((to.0 - from.0).signum(), (to.1 - from.1).signum());
+    let (mut x, mut y) = from;
+    let mut error = dx + dy;
+    let mut tiles = Vec::new();
+    while (x, y) != to {
+        let e2 = 2 * error;
+        if e2 >= dy {
+            error += dy;
+            x += sx;
+        }
+        if e2 <= dx {
+            error += dx;
+            y += sy;
+        }
+        tiles.push((x, y));
+    }
+    tiles
+}
+
+/// What a tile is made of, which decides whether it can be walked on and seen through
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub enum TileKind {
+    Wall,
+    Floor,
+    /// opened by bumping into it, closed it blocks the way and the view
+    Door { open: bool },
+    /// a closed door only the player can force open
+    LockedDoor,
+    /// looks like floor until something steps on it
+    Trap { revealed: bool },
+    /// fallen stones, easy to see over but in the way until the player clears them
+    Rubble,
+}
+
+impl TileKind {
+    fn blocked(&self) -> bool {
+        match *self {
+            TileKind::Wall | TileKind::Door { open: false } | TileKind::LockedDoor | TileKind::Rubble => true,
+            _ => false,
+        }
+    }
+
+    fn block_sight(&self) -> bool {
+        match *self {
+            TileKind::Wall | TileKind::Door { open: false } | TileKind::LockedDoor => true,
+            _ => false,
+        }
+    }
 
-#[derive(Clone, Copy)]
+    /// What is drawn over the tile's background, if anything
+    fn glyph(&self) -> Option<(char, tcod::Color)> {
+        match *self {
+            TileKind::Door { open: false } => Some(('+', COLOR_DOOR)),
+            TileKind::Door { open: true } => Some(('\'', COLOR_DOOR)),
+            TileKind::LockedDoor => Some(('+', COLOR_LOCKED_DOOR)),
+            TileKind::Trap { revealed: true } => Some(('^', COLOR_TRAP)),
+            TileKind::Rubble => Some((':', COLOR_RUBBLE)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
 struct Tile {
-    blocked: bool,
-    block_sight: bool,
+    kind: TileKind,
     explored: bool,
 }
 
 impl Tile {
-    fn empty() -> Self {
+    fn new(kind: TileKind) -> Self {
         Tile {
-            blocked: false,
-            block_sight: false,
+            kind: kind,
             explored: false,
         }
     }
 
+    fn empty() -> Self {
+        Tile::new(TileKind::Floor)
+    }
+
     fn wall() -> Self {
-        Tile {
-            blocked: true,
-            block_sight: true,
-            explored: false,
+        Tile::new(TileKind::Wall)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub enum Stairs {
+    Up,
+    Down,
+}
+
+impl Stairs {
+    fn glyph(&self) -> char {
+        match *self {
+            Stairs::Up => '<',
+            Stairs::Down => '>',
         }
     }
 }
 
+/// The saved form of a `Map`, the fov map is rebuilt from the tiles on load
+#[derive(Serialize, Deserialize)]
+pub struct MapData {
+    map: Vec<Tile>,
+    width: i32,
+    height: i32,
+    stairs_up: Option<(i32, i32)>,
+    stairs_down: Option<(i32, i32)>,
+}
+
 pub struct Map {
     map: Vec<Tile>,
     width: i32,
     height: i32,
+    stairs_up: Option<(i32, i32)>,
+    stairs_down: Option<(i32, i32)>,
+    /// light reaching every tile, from all the light sources of the floor
+    light: Vec<tcod::Color>,
+    /// tiles in the viewer's line of sight with enough light to see them
+    visible: Vec<bool>,
     fov: std::sync::Arc<std::sync::Mutex<tcod::map::Map>>,
 }
 
@@ -65,10 +197,59 @@ impl Map {
             map: vec![Tile::wall(); (width * height) as usize],
             width: width,
             height: height,
+            stairs_up: None,
+            stairs_down: None,
+            light: vec![tcod::colors::BLACK; (width * height) as usize],
+            visible: vec![false; (width * height) as usize],
             fov: std::sync::Arc::new(std::sync::Mutex::new(tcod::map::Map::new(width, height))),
         }
     }
 
+    pub fn save(&self) -> MapData {
+        MapData {
+            map: self.map.clone(),
+            width: self.width,
+            height: self.height,
+            stairs_up: self.stairs_up,
+            stairs_down: self.stairs_down,
+        }
+    }
+
+    pub fn load(data: MapData) -> Map {
+        let mut map = Map::new(data.width, data.height);
+        map.map = data.map;
+        map.stairs_up = data.stairs_up;
+        map.stairs_down = data.stairs_down;
+        map.refresh_fov_map();
+        map
+    }
+
+    pub fn width(&self) -> i32 {
+        self.width
+    }
+
+    pub fn height(&self) -> i32 {
+        self.height
+    }
+
+    /// Position of the stairs going in the given direction
+    pub fn stairs(&self, direction: Stairs) -> Option<(i32, i32)> {
+        match direction {
+            Stairs::Up => self.stairs_up,
+            Stairs::Down => self.stairs_down,
+        }
+    }
+
+    pub fn stairs_at(&self, x: i32, y: i32) -> Option<Stairs> {
+        if self.stairs_up == Some((x, y)) {
+            Some(Stairs::Up)
+        } else if self.stairs_down == Some((x, y)) {
+            Some(Stairs::Down)
+        } else {
+            None
+        }
+    }
+
     fn get(&self, x: i32, y: i32) -> &Tile {
         &self.map[(x + y * self.width) as usize]
     }
@@ -77,7 +258,37 @@ impl Map {
         &mut self.map[(x + y * self.width) as usize]
     }
 
-    fn create_room(&mut self, room: rect::Rect) {
+    /// What the tile is made of, anything out of the map is wall
+    pub fn tile_at(&self, x: i32, y: i32) -> TileKind {
+        if x < 0 || x >= self.width || y < 0 || y >= self.height {
+            TileKind::Wall
+        } else {
+            self.get(x, y).kind
+        }
+    }
+
+    /// Changes what the tile is made of, keeping whether it was explored, and updates the fov map.
+    /// The field of view itself is only recomputed by `recompute_fov`.
+    pub fn set_tile(&mut self, x: i32, y: i32, kind: TileKind) {
+        self.get_mut(x, y).kind = kind;
+        self.fov
+            .lock()
+            .unwrap()
+            .set(x, y, !kind.block_sight(), !kind.blocked());
+    }
+
+    /// Whether a closed door that anyone can open is there
+    pub fn can_open(&self, x: i32, y: i32) -> bool {
+        self.tile_at(x, y) == TileKind::Door { open: false }
+    }
+
+    /// Turns a single tile into floor
+    pub fn dig(&mut self, x: i32, y: i32) {
+        std::mem::swap(self.get_mut(x, y), &mut Tile::empty());
+    }
+
+    /// Digs the inside of `room`, its edges stay walls
+    pub fn create_room(&mut self, room: rect::Rect) {
         for x in (room.x1 + 1)..room.x2 {
             for y in (room.y1 + 1)..room.y2 {
                 std::mem::swap(self.get_mut(x, y), &mut Tile::empty());
@@ -85,120 +296,183 @@ impl Map {
         }
     }
 
-    fn create_h_tunnel(&mut self, x1: i32, x2: i32, y: i32) {
+    pub fn create_h_tunnel(&mut self, x1: i32, x2: i32, y: i32) {
         for x in std::cmp::min(x1, x2)..=std::cmp::max(x1, x2) {
             std::mem::swap(self.get_mut(x, y), &mut Tile::empty());
         }
     }
 
-    fn create_v_tunnel(&mut self, y1: i32, y2: i32, x: i32) {
+    pub fn create_v_tunnel(&mut self, y1: i32, y2: i32, x: i32) {
         for y in std::cmp::min(y1, y2)..=std::cmp::max(y1, y2) {
             std::mem::swap(self.get_mut(x, y), &mut Tile::empty());
         }
     }
 
-    pub fn recompute_fov(&mut self, x: i32, y: i32) {
-        self.fov
-            .lock()
-            .unwrap()
-            .compute_fov(x, y, TORCH_RADIUS, FOV_LIGHT_WALLS, FOV_ALGO);
+    /// Lights the floor from every source, then sees what is in sight from (x, y) and lit enough
+    pub fn recompute_fov(&mut self, x: i32, y: i32, lights: &[((i32, i32), LightSource)]) {
+        let mut fov = self.fov.lock().unwrap();
+        for light in self.light.iter_mut() {
+            *light = tcod::colors::BLACK;
+        }
+        for &((lx, ly), source) in lights.iter() {
+            fov.compute_fov(lx, ly, source.radius, FOV_LIGHT_WALLS, FOV_ALGO);
+            for ty in std::cmp::max(0, ly - source.radius)..std::cmp::min(self.height, ly + source.radius + 1) {
+                for tx in std::cmp::max(0, lx - source.radius)..std::cmp::min(self.width, lx + source.radius + 1) {
+                    let distance = (((tx - lx).pow(2) + (ty - ly).pow(2)) as f32).sqrt();
+                    if distance > source.radius as f32 || !fov.is_in_fov(tx, ty) {
+                        continue;
+                    }
+                    let intensity = (1.0 - distance / (source.radius + 1) as f32).powf(source.falloff);
+                    let i = (tx + ty * self.width) as usize;
+                    self.light[i] = add_light(self.light[i], source.color, intensity);
+                }
+            }
+        }
+
+        // the viewer's line of sight is only bounded by the walls
+        fov.compute_fov(x, y, 0, FOV_LIGHT_WALLS, FOV_ALGO);
         for y in 0..self.height {
             for x in 0..self.width {
-                let visible = self.fov.lock().unwrap().is_in_fov(x, y);
-                if visible {
-                    self.get_mut(x, y).explored = true;
+                let i = (x + y * self.width) as usize;
+                let light = self.light[i];
+                let bright = light.r as i32 + light.g as i32 + light.b as i32 >= MIN_LIGHT;
+                self.visible[i] = bright && fov.is_in_fov(x, y);
+                if self.visible[i] {
+                    self.map[i].explored = true;
                 }
             }
         }
     }
 
     pub fn is_in_fov(&self, x: i32, y: i32) -> bool {
-        self.fov.lock().unwrap().is_in_fov(x, y)
+        if x < 0 || x >= self.width || y < 0 || y >= self.height {
+            false
+        } else {
+            self.visible[(x + y * self.width) as usize]
+        }
     }
 
-    pub fn generate_map<T>(&mut self, rng: &mut tcod::random::Rng, mut create_objects: T) -> (i32, i32)
-        where T: (FnMut(&rect::Rect, &mut tcod::random::Rng) -> ())
+    /// Carves a new level with `generator`, puts the stairs and returns where the player starts
+    pub fn generate_map<T>(&mut self, generator: &mut MapGenerator, rng: &mut tcod::random::Rng, mut create_objects: T) -> (i32, i32)
+        where T: (FnMut(&SpawnRegion, &mut tcod::random::Rng) -> ())
     {
-        let mut rooms = vec![];
-
-        let mut start = (0, 0);
-
-        for _ in 0..MAX_ROOMS {
-            // random width and height
-            let w = rng.get_int(ROOM_MIN_SIZE, ROOM_MAX_SIZE + 1);
-            let h = rng.get_int(ROOM_MIN_SIZE, ROOM_MAX_SIZE + 1);
-            // random position without going out of the boundaries of the map
-            let x = rng.get_int(0, self.width - w);
-            let y = rng.get_int(0, self.height - h);
-
-            let new_room = rect::Rect::new(x, y, w, h);
-            // run through the other rooms and see if they intersect with this one
-            let failed = rooms
-                .iter()
-                .any(|other_room| new_room.intersects_with(other_room));
-            if !failed {
-                // "paint" it to the map's tiles
-                self.create_room(new_room);
-                create_objects(&new_room, rng);
-                let (new_x, new_y) = new_room.center();
-                if rooms.is_empty() {
-                    start = (new_x, new_y);
-                } else {
-                    // center coordinates of the previous room
-                    let (prev_x, prev_y) = rooms[rooms.len() - 1].center();
-
-                    // draw a coin (random bool value -- either true or false)
-                    if rng.get_int(0, 2) == 1 {
-                        // first move horizontally, then vertically
-                        self.create_h_tunnel(prev_x, new_x, prev_y);
-                        self.create_v_tunnel(prev_y, new_y, new_x);
-                    } else {
-                        // first move vertically, then horizontally
-                        self.create_v_tunnel(prev_y, new_y, prev_x);
-                        self.create_h_tunnel(prev_x, new_x, new_y);
-                    }
-                }
-                rooms.push(new_room);
-            }
-        }
+        let layout = generator.generate(self, rng, &mut create_objects);
+        mapgen::scatter_hazards(self, rng, &layout);
+
+        // the way back up is where the player arrives
+        self.stairs_up = Some(layout.start);
+        self.stairs_down = layout.exit;
 
+        self.refresh_fov_map();
+        layout.start
+    }
+
+    /// Copies the transparency and walkability of every tile into the fov map
+    fn refresh_fov_map(&mut self) {
         for y in 0..self.height {
             for x in 0..self.width {
                 self.fov.lock().unwrap().set(
                     x,
                     y,
-                    !self.get(x, y).block_sight,
-                    !self.get(x, y).blocked,
+                    !self.get(x, y).kind.block_sight(),
+                    !self.get(x, y).kind.blocked(),
                 );
             }
         }
-        self.recompute_fov(start.0, start.1);
-        start
+    }
+
+    /// A map dug out of rows where `#` is rock, `>` the way down and anything else floor
+    #[cfg(test)]
+    pub fn from_rows(rows: &[&str]) -> Map {
+        let mut map = Map::new(rows[0].len() as i32, rows.len() as i32);
+        for (y, row) in rows.iter().enumerate() {
+            for (x, c) in row.chars().enumerate() {
+                let (x, y) = (x as i32, y as i32);
+                match c {
+                    '#' => {}
+                    '>' => {
+                        map.dig(x, y);
+                        map.stairs_down = Some((x, y));
+                    }
+                    _ => map.dig(x, y),
+                }
+            }
+        }
+        map.refresh_fov_map();
+        map
     }
 
     pub fn can_walk(&self, x: i32, y: i32) -> bool {
         if x < 0 || x >= self.width || y < 0 || y >= self.height {
             false
         } else {
-            !self.map[(x + y * self.width) as usize].blocked
+            !self.map[(x + y * self.width) as usize].kind.blocked()
         }
     }
 
-    pub fn render(&self, con: &mut tcod::Console) {
+    pub fn render(&self, con: &mut Screen) {
         for y in 0..self.height {
             for x in 0..self.width {
                 if self.get(x, y).explored {
-                    let visible = self.fov.lock().unwrap().is_in_fov(x, y);
-                    let wall = self.get(x, y).block_sight;
-                    let color = match (visible, wall) {
+                    let i = (x + y * self.width) as usize;
+                    let wall = self.get(x, y).kind.block_sight();
+                    // what is in sight takes the color of the light falling on it
+                    let color = match (self.visible[i], wall) {
                         (false, false) => COLOR_DARK_GROUND,
                         (false, true) => COLOR_DARK_WALL,
-                        (true, false) => COLOR_LIGHT_GROUND,
-                        (true, true) => COLOR_LIGHT_WALL,
+                        (true, false) => tint(COLOR_DARK_GROUND, COLOR_LIGHT_GROUND, self.light[i]),
+                        (true, true) => tint(COLOR_DARK_WALL, COLOR_LIGHT_WALL, self.light[i]),
                     };
-                    con.set_char_background(x, y, color, tcod::BackgroundFlag::Set);
+                    con.set_background(x, y, color);
+                    if let Some((glyph, color)) = self.get(x, y).kind.glyph() {
+                        con.put_char(x, y, glyph, color);
+                    }
+                    if let Some(stairs) = self.stairs_at(x, y) {
+                        con.put_char(x, y, stairs.glyph(), tcod::colors::WHITE);
+                    }
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use screen::{Cell, Grid};
+
+    fn light(radius: i32) -> LightSource {
+        LightSource {
+            radius: radius,
+            color: tcod::colors::WHITE,
+            falloff: 1.0,
+        }
+    }
+
+    #[test]
+    fn render_draws_what_is_in_sight_and_lit() {
+        let mut map = Map::from_rows(&["#######", "#..#..#", "#######"]);
+        map.set_tile(3, 1, TileKind::Door { open: false });
+        map.recompute_fov(1, 1, &[((1, 1), light(5))]);
+        let mut grid = Grid::new(7, 3);
+        map.render(&mut grid);
+
+        let lit = grid.cell(1, 1).bg;
+        assert!(lit != tcod::colors::BLACK && lit != COLOR_DARK_GROUND);
+        assert_eq!(grid.cell(3, 1).glyph, '+');
+        // behind the closed door, never seen
+        assert_eq!(grid.cell(5, 1), Cell::blank());
+    }
+
+    #[test]
+    fn tiles_in_sight_stay_hidden_in_the_dark() {
+        let mut map = Map::from_rows(&["########", "#......#", "########"]);
+        map.recompute_fov(1, 1, &[((1, 1), light(1))]);
+        assert!(map.is_in_fov(2, 1));
+        assert!(!map.is_in_fov(5, 1));
+
+        let mut grid = Grid::new(8, 3);
+        map.render(&mut grid);
+        assert_eq!(grid.cell(5, 1), Cell::blank());
+    }
+}
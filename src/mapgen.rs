@@ -0,0 +1,571 @@
+use std;
+
+use tcod::random::Rng;
+
+use map::{Map, Stairs, TileKind};
+use rect::Rect;
+
+const ROOM_MAX_SIZE: i32 = 10;
+const ROOM_MIN_SIZE: i32 = 6;
+const MAX_ROOMS: i32 = 30;
+
+/// Smallest area a BSP split may leave on either side
+const BSP_MIN_LEAF: i32 = 10;
+
+/// Caves are cut in squares of this size to be handed out as spawn regions
+const CAVE_REGION_SIZE: i32 = 10;
+/// Pieces of cave smaller than this get nothing spawned in them
+const CAVE_REGION_MIN_TILES: usize = 12;
+
+/// Odds out of 100 that a tunnel entering a room gets a door, and that this door is locked
+const DOOR_CHANCE: i32 = 60;
+const LOCKED_DOOR_CHANCE: i32 = 10;
+
+const MAX_TRAPS: i32 = 3;
+const MAX_RUBBLE: i32 = 4;
+
+/// Part of a level where monsters and items can be spawned
+pub enum SpawnRegion {
+    /// a rectangular room, its edges are walls
+    Room(Rect),
+    /// any set of floor tiles, like a patch of cave
+    Area(Vec<(i32, i32)>),
+    /// the exact places a vault wants one monster and one item in
+    Spots {
+        monsters: Vec<(i32, i32)>,
+        items: Vec<(i32, i32)>,
+    },
+}
+
+impl SpawnRegion {
+    /// A random floor tile of the region
+    pub fn random_spot(&self, rng: &mut Rng) -> (i32, i32) {
+        match *self {
+            SpawnRegion::Room(ref room) => (
+                rng.get_int(room.x1 + 1, room.x2 - 1),
+                rng.get_int(room.y1 + 1, room.y2 - 1),
+            ),
+            SpawnRegion::Area(ref tiles) => tiles[rng.get_int(0, tiles.len() as i32 - 1) as usize],
+            SpawnRegion::Spots { ref monsters, ref items } => {
+                let spots: Vec<&(i32, i32)> = monsters.iter().chain(items.iter()).collect();
+                *spots[rng.get_int(0, spots.len() as i32 - 1) as usize]
+            }
+        }
+    }
+}
+
+/// Where the player arrives on a generated level and where the way down is, if there is one
+pub struct Layout {
+    pub start: (i32, i32),
+    pub exit: Option<(i32, i32)>,
+}
+
+/// An algorithm carving a level out of a map full of walls
+pub trait MapGenerator {
+    /// Carves `map` and reports every region to `create_objects` once it is carved
+    fn generate(&mut self, map: &mut Map, rng: &mut Rng, create_objects: &mut FnMut(&SpawnRegion, &mut Rng)) -> Layout;
+}
+
+/// Joins two points with an L-shaped tunnel, bending on a coin flip
+fn connect(map: &mut Map, rng: &mut Rng, (x1, y1): (i32, i32), (x2, y2): (i32, i32)) {
+    if rng.get_int(0, 2) == 1 {
+        // first move horizontally, then vertically
+        map.create_h_tunnel(x1, x2, y1);
+        map.create_v_tunnel(y1, y2, x2);
+    } else {
+        // first move vertically, then horizontally
+        map.create_v_tunnel(y1, y2, x1);
+        map.create_h_tunnel(x1, x2, y2);
+    }
+}
+
+/// Puts doors where tunnels go through the walls of the rooms
+fn add_doors(map: &mut Map, rng: &mut Rng, rooms: &[Rect]) {
+    for room in rooms {
+        // corners are left out, a tunnel never goes through them alone
+        let mut walls = Vec::new();
+        for x in (room.x1 + 1)..room.x2 {
+            walls.push(((x, room.y1), (1, 0)));
+            walls.push(((x, room.y2), (1, 0)));
+        }
+        for y in (room.y1 + 1)..room.y2 {
+            walls.push(((room.x1, y), (0, 1)));
+            walls.push(((room.x2, y), (0, 1)));
+        }
+        for ((x, y), (dx, dy)) in walls {
+            // a doorway has wall on both sides, otherwise the tunnel runs along the wall
+            let doorway = map.tile_at(x, y) == TileKind::Floor
+                && map.tile_at(x - dx, y - dy) == TileKind::Wall
+                && map.tile_at(x + dx, y + dy) == TileKind::Wall;
+            if doorway && rng.get_int(1, 100) <= DOOR_CHANCE {
+                let kind = if rng.get_int(1, 100) <= LOCKED_DOOR_CHANCE {
+                    TileKind::LockedDoor
+                } else {
+                    TileKind::Door { open: false }
+                };
+                map.set_tile(x, y, kind);
+            }
+        }
+    }
+}
+
+/// Hides a few traps and drops some rubble on the floor, away from the start and the exit
+pub fn scatter_hazards(map: &mut Map, rng: &mut Rng, layout: &Layout) {
+    let mut floors = Vec::new();
+    for y in 0..map.height() {
+        for x in 0..map.width() {
+            let spot = Some((x, y));
+            if map.tile_at(x, y) == TileKind::Floor && (x, y) != layout.start && spot != layout.exit {
+                floors.push((x, y));
+            }
+        }
+    }
+    if floors.is_empty() {
+        return;
+    }
+    let traps = rng.get_int(0, MAX_TRAPS);
+    let rubble = rng.get_int(0, MAX_RUBBLE);
+    for i in 0..(traps + rubble) {
+        let (x, y) = floors[rng.get_int(0, floors.len() as i32 - 1) as usize];
+        let kind = if i < traps { TileKind::Trap { revealed: false } } else { TileKind::Rubble };
+        map.set_tile(x, y, kind);
+    }
+}
+
+/// Rooms thrown at random spots, dropped when they overlap, each one tunneled to the previous one
+pub struct RandomRooms;
+
+impl MapGenerator for RandomRooms {
+    fn generate(&mut self, map: &mut Map, rng: &mut Rng, create_objects: &mut FnMut(&SpawnRegion, &mut Rng)) -> Layout {
+        let mut rooms: Vec<Rect> = vec![];
+
+        for _ in 0..MAX_ROOMS {
+            // random width and height
+            let w = rng.get_int(ROOM_MIN_SIZE, ROOM_MAX_SIZE + 1);
+            let h = rng.get_int(ROOM_MIN_SIZE, ROOM_MAX_SIZE + 1);
+            // random position without going out of the boundaries of the map
+            let x = rng.get_int(0, map.width() - w);
+            let y = rng.get_int(0, map.height() - h);
+
+            let new_room = Rect::new(x, y, w, h);
+            // run through the other rooms and see if they intersect with this one
+            let failed = rooms
+                .iter()
+                .any(|other_room| new_room.intersects_with(other_room));
+            if !failed {
+                // "paint" it to the map's tiles
+                map.create_room(new_room);
+                create_objects(&SpawnRegion::Room(new_room), rng);
+                if let Some(previous) = rooms.last() {
+                    connect(map, rng, previous.center(), new_room.center());
+                }
+                rooms.push(new_room);
+            }
+        }
+
+        add_doors(map, rng, &rooms);
+        layout(&rooms)
+    }
+}
+
+/// Recursively splits the map in two, one room per leaf, and tunnels between siblings so every
+/// part of the tree is connected. Rooms end up spread evenly over the whole map.
+pub struct Bsp;
+
+impl Bsp {
+    /// Carves the rooms of `area`, returns one of them for the parent to connect to
+    fn build(&self, map: &mut Map, rng: &mut Rng, area: Rect, rooms: &mut Vec<Rect>, create_objects: &mut FnMut(&SpawnRegion, &mut Rng)) -> Rect {
+        let (width, height) = (area.x2 - area.x1, area.y2 - area.y1);
+        let split_across = width >= 2 * BSP_MIN_LEAF;
+        let split_down = height >= 2 * BSP_MIN_LEAF;
+
+        if !split_across && !split_down {
+            let w = rng.get_int(ROOM_MIN_SIZE, std::cmp::min(ROOM_MAX_SIZE, width));
+            let h = rng.get_int(ROOM_MIN_SIZE, std::cmp::min(ROOM_MAX_SIZE, height));
+            let x = rng.get_int(area.x1, area.x2 - w);
+            let y = rng.get_int(area.y1, area.y2 - h);
+            let room = Rect::new(x, y, w, h);
+            map.create_room(room);
+            create_objects(&SpawnRegion::Room(room), rng);
+            rooms.push(room);
+            return room;
+        }
+
+        // cut the longest side, so the leaves stay roughly square
+        let across = if split_across && split_down { width >= height } else { split_across };
+        let (first, second) = if across {
+            let x = rng.get_int(area.x1 + BSP_MIN_LEAF, area.x2 - BSP_MIN_LEAF);
+            (Rect { x2: x, ..area }, Rect { x1: x, ..area })
+        } else {
+            let y = rng.get_int(area.y1 + BSP_MIN_LEAF, area.y2 - BSP_MIN_LEAF);
+            (Rect { y2: y, ..area }, Rect { y1: y, ..area })
+        };
+        let a = self.build(map, rng, first, rooms, create_objects);
+        let b = self.build(map, rng, second, rooms, create_objects);
+        connect(map, rng, a.center(), b.center());
+        if rng.get_int(0, 1) == 1 { a } else { b }
+    }
+}
+
+impl MapGenerator for Bsp {
+    fn generate(&mut self, map: &mut Map, rng: &mut Rng, create_objects: &mut FnMut(&SpawnRegion, &mut Rng)) -> Layout {
+        // the outer edge of the map stays solid
+        let area = Rect::new(0, 0, map.width() - 1, map.height() - 1);
+        let mut rooms = Vec::new();
+        self.build(map, rng, area, &mut rooms, create_objects);
+        add_doors(map, rng, &rooms);
+        layout(&rooms)
+    }
+}
+
+/// Organic caves grown by a cellular automaton: the map starts as random noise, then every tile
+/// becomes whatever most of its neighbours are, a few times over.
+pub struct Caves {
+    /// share of the tiles that start as walls
+    pub fill_ratio: f32,
+    /// smoothing passes of the automaton
+    pub iterations: u32,
+}
+
+impl Caves {
+    pub fn new() -> Caves {
+        Caves {
+            fill_ratio: 0.45,
+            iterations: 4,
+        }
+    }
+
+    /// Walls among the 8 neighbours of a tile, the outside of the map counts as walls
+    fn walls_around(walls: &[bool], width: i32, height: i32, x: i32, y: i32) -> usize {
+        let mut count = 0;
+        for ny in (y - 1)..(y + 2) {
+            for nx in (x - 1)..(x + 2) {
+                if (nx, ny) == (x, y) {
+                    continue;
+                }
+                if nx < 0 || ny < 0 || nx >= width || ny >= height || walls[(nx + ny * width) as usize] {
+                    count += 1;
+                }
+            }
+        }
+        count
+    }
+}
+
+impl MapGenerator for Caves {
+    fn generate(&mut self, map: &mut Map, rng: &mut Rng, create_objects: &mut FnMut(&SpawnRegion, &mut Rng)) -> Layout {
+        let (width, height) = (map.width(), map.height());
+        let on_edge = |x: i32, y: i32| x == 0 || y == 0 || x == width - 1 || y == height - 1;
+
+        let threshold = (self.fill_ratio * 1000.0) as i32;
+        let mut walls: Vec<bool> = (0..width * height)
+            .map(|i| on_edge(i % width, i / width) || rng.get_int(0, 999) < threshold)
+            .collect();
+
+        for _ in 0..self.iterations {
+            let next = (0..width * height)
+                .map(|i| {
+                    let (x, y) = (i % width, i / width);
+                    match Caves::walls_around(&walls, width, height, x, y) {
+                        _ if on_edge(x, y) => true,
+                        n if n >= 5 => true,
+                        n if n <= 3 => false,
+                        _ => walls[i as usize],
+                    }
+                })
+                .collect();
+            walls = next;
+        }
+
+        // only the biggest cave is kept, the islands around it stay solid rock
+        let cave = flood_regions(&walls, width, height)
+            .into_iter()
+            .max_by_key(|region| region.len())
+            .unwrap_or_else(Vec::new);
+        if cave.is_empty() {
+            return Layout { start: (0, 0), exit: None };
+        }
+        for &(x, y) in cave.iter() {
+            map.dig(x, y);
+        }
+
+        // caves have no rooms, they are cut in squares instead
+        let mut chunks: Vec<((i32, i32), Vec<(i32, i32)>)> = Vec::new();
+        for &(x, y) in cave.iter() {
+            let key = (x / CAVE_REGION_SIZE, y / CAVE_REGION_SIZE);
+            match chunks.iter().position(|&(k, _)| k == key) {
+                Some(i) => chunks[i].1.push((x, y)),
+                None => chunks.push((key, vec![(x, y)])),
+            }
+        }
+        for (_, tiles) in chunks {
+            if tiles.len() >= CAVE_REGION_MIN_TILES {
+                create_objects(&SpawnRegion::Area(tiles), rng);
+            }
+        }
+
+        // the way down is as far as it gets from the start
+        let start = cave[rng.get_int(0, cave.len() as i32 - 1) as usize];
+        let exit = farthest_tile(map, start);
+        Layout {
+            start: start,
+            exit: if exit != start { Some(exit) } else { None },
+        }
+    }
+}
+
+/// Groups the open tiles in 4-connected regions
+fn flood_regions(walls: &[bool], width: i32, height: i32) -> Vec<Vec<(i32, i32)>> {
+    let mut seen = vec![false; walls.len()];
+    let mut regions = Vec::new();
+    for i in 0..walls.len() {
+        if walls[i] || seen[i] {
+            continue;
+        }
+        let mut region = Vec::new();
+        let mut stack = vec![(i as i32 % width, i as i32 / width)];
+        seen[i] = true;
+        while let Some((x, y)) = stack.pop() {
+            region.push((x, y));
+            for &(nx, ny) in [(x + 1, y), (x - 1, y), (x, y + 1), (x, y - 1)].iter() {
+                if nx < 0 || ny < 0 || nx >= width || ny >= height {
+                    continue;
+                }
+                let n = (nx + ny * width) as usize;
+                if !walls[n] && !seen[n] {
+                    seen[n] = true;
+                    stack.push((nx, ny));
+                }
+            }
+        }
+        // keep the tiles in reading order, so regions come out the same for the same seed
+        region.sort_by_key(|&(x, y)| (y, x));
+        regions.push(region);
+    }
+    regions
+}
+
+/// Walkable tile the furthest away from `from`, in steps
+fn farthest_tile(map: &Map, from: (i32, i32)) -> (i32, i32) {
+    let mut distances = vec![-1; (map.width() * map.height()) as usize];
+    let mut queue = std::collections::VecDeque::new();
+    distances[(from.0 + from.1 * map.width()) as usize] = 0;
+    queue.push_back(from);
+    let mut farthest = from;
+    while let Some((x, y)) = queue.pop_front() {
+        farthest = (x, y);
+        let distance = distances[(x + y * map.width()) as usize];
+        for &(nx, ny) in [(x + 1, y), (x - 1, y), (x, y + 1), (x, y - 1)].iter() {
+            if map.can_walk(nx, ny) && distances[(nx + ny * map.width()) as usize] < 0 {
+                distances[(nx + ny * map.width()) as usize] = distance + 1;
+                queue.push_back((nx, ny));
+            }
+        }
+    }
+    farthest
+}
+
+/// The player starts in the first room, the way down is in the last one
+fn layout(rooms: &[Rect]) -> Layout {
+    Layout {
+        start: rooms.first().map_or((0, 0), |room| room.center()),
+        exit: if rooms.len() > 1 { rooms.last().map(|room| room.center()) } else { None },
+    }
+}
+
+/// What `check_level` changed to make a level sound
+pub struct Repairs {
+    /// pockets of the level that could not be reached and got tunneled to the rest
+    pub tunnels: usize,
+    /// entities moved, by index in the spots given to `check_level`, and where they went
+    pub moved: Vec<(usize, (i32, i32))>,
+}
+
+impl Repairs {
+    /// A line telling what was fixed, if anything was
+    pub fn report(&self) -> Option<String> {
+        if self.tunnels == 0 && self.moved.is_empty() {
+            return None;
+        }
+        Some(format!(
+            "dug {} tunnel(s) to unreachable areas, moved {} entit(ies) off walls or shared tiles",
+            self.tunnels,
+            self.moved.len()
+        ))
+    }
+}
+
+/// Tiles reachable from `from`, anything but rock can be got through by the player
+fn reachable(map: &Map, from: (i32, i32)) -> Vec<bool> {
+    let width = map.width();
+    let mut reached = vec![false; (width * map.height()) as usize];
+    let mut stack = vec![from];
+    reached[(from.0 + from.1 * width) as usize] = true;
+    while let Some((x, y)) = stack.pop() {
+        for &(nx, ny) in [(x + 1, y), (x - 1, y), (x, y + 1), (x, y - 1)].iter() {
+            if map.tile_at(nx, ny) == TileKind::Wall {
+                continue;
+            }
+            let n = (nx + ny * width) as usize;
+            if !reached[n] {
+                reached[n] = true;
+                stack.push((nx, ny));
+            }
+        }
+    }
+    reached
+}
+
+/// Digs the shortest way from `from` to a reached tile, staying off the edge of the map
+fn tunnel_to_reached(map: &mut Map, reached: &[bool], from: (i32, i32)) -> bool {
+    let (width, height) = (map.width(), map.height());
+    let mut previous: Vec<Option<(i32, i32)>> = vec![None; (width * height) as usize];
+    let mut queue = std::collections::VecDeque::new();
+    previous[(from.0 + from.1 * width) as usize] = Some(from);
+    queue.push_back(from);
+    while let Some((x, y)) = queue.pop_front() {
+        if reached[(x + y * width) as usize] {
+            let mut tile = previous[(x + y * width) as usize].unwrap();
+            while tile != from {
+                if map.tile_at(tile.0, tile.1) == TileKind::Wall {
+                    map.set_tile(tile.0, tile.1, TileKind::Floor);
+                }
+                tile = previous[(tile.0 + tile.1 * width) as usize].unwrap();
+            }
+            return true;
+        }
+        for &(nx, ny) in [(x + 1, y), (x - 1, y), (x, y + 1), (x, y - 1)].iter() {
+            if nx < 1 || ny < 1 || nx >= width - 1 || ny >= height - 1 {
+                continue;
+            }
+            let n = (nx + ny * width) as usize;
+            if previous[n].is_none() {
+                previous[n] = Some((x, y));
+                queue.push_back((nx, ny));
+            }
+        }
+    }
+    false
+}
+
+/// Checks that a freshly generated level is sound and fixes what it can: every open tile must be
+/// reachable from `start`, the way down too, and every spawned entity must stand on its own
+/// walkable tile. `spots` are where the entities are and whether they block.
+///
+/// Fails with the reason when the level is beyond repair and has to be generated again.
+pub fn check_level(map: &mut Map, start: (i32, i32), spots: &[((i32, i32), bool)]) -> Result<Repairs, String> {
+    let (width, height) = (map.width(), map.height());
+    // generators fall back on (0, 0), in the rock, when they could not carve anything
+    if !map.can_walk(start.0, start.1) {
+        return Err(format!("has nowhere to start, {:?} is not walkable", start));
+    }
+    let exit = match map.stairs(Stairs::Down) {
+        Some(exit) if exit != start => exit,
+        _ => return Err("has no way down".into()),
+    };
+
+    let mut repairs = Repairs {
+        tunnels: 0,
+        moved: Vec::new(),
+    };
+
+    // connect the unreachable pockets one at a time, each tunnel may reach several of them
+    let mut reached = reachable(map, start);
+    loop {
+        let pocket = (0..width * height)
+            .find(|&i| !reached[i as usize] && map.tile_at(i % width, i / width) != TileKind::Wall)
+            .map(|i| (i % width, i / width));
+        let pocket = match pocket {
+            Some(pocket) => pocket,
+            None => break,
+        };
+        if !tunnel_to_reached(map, &reached, pocket) {
+            return Err(format!("has no way to {:?}", pocket));
+        }
+        repairs.tunnels += 1;
+        reached = reachable(map, start);
+    }
+    if !reached[(exit.0 + exit.1 * width) as usize] {
+        return Err(format!("has the way down in the rock at {:?}", exit));
+    }
+
+    // blockers need a tile of their own, the player's start included
+    let mut taken: Vec<(i32, i32)> = vec![start];
+    for (i, &((x, y), blocks)) in spots.iter().enumerate() {
+        let free = |taken: &[(i32, i32)], x: i32, y: i32| map.can_walk(x, y) && !(blocks && taken.contains(&(x, y)));
+        if free(&taken, x, y) {
+            if blocks {
+                taken.push((x, y));
+            }
+            continue;
+        }
+        // closest free tile, by straight distance
+        let spot = (0..width * height)
+            .map(|i| (i % width, i / width))
+            .filter(|&(tx, ty)| reached[(tx + ty * width) as usize] && free(&taken, tx, ty))
+            .min_by_key(|&(tx, ty)| (tx - x).pow(2) + (ty - y).pow(2));
+        match spot {
+            Some(spot) => {
+                if blocks {
+                    taken.push(spot);
+                }
+                repairs.moved.push((i, spot));
+            }
+            None => return Err("has no room left for everything spawned on it".into()),
+        }
+    }
+
+    Ok(repairs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_level_tunnels_to_an_isolated_pocket() {
+        let mut map = Map::from_rows(&[
+            "###########",
+            "#...#######",
+            "#..>###..##",
+            "#...###..##",
+            "###########",
+        ]);
+        assert!(!reachable(&map, (1, 1))[7 + 2 * 11]);
+        let repairs = check_level(&mut map, (1, 1), &[]).unwrap();
+        assert_eq!(repairs.tunnels, 1);
+        assert!(repairs.moved.is_empty());
+        assert!(reachable(&map, (1, 1))[7 + 2 * 11]);
+    }
+
+    #[test]
+    fn check_level_splits_blockers_sharing_a_tile() {
+        let mut map = Map::from_rows(&[
+            "#######",
+            "#.....#",
+            "#.....#",
+            "#....>#",
+            "#######",
+        ]);
+        let spots = [((3, 2), true), ((3, 2), true), ((3, 2), false), ((0, 0), false)];
+        let repairs = check_level(&mut map, (1, 1), &spots).unwrap();
+        assert_eq!(repairs.tunnels, 0);
+        assert_eq!(repairs.moved.len(), 2);
+
+        // the second blocker goes next door, the item may share its tile
+        let (index, (x, y)) = repairs.moved[0];
+        assert_eq!(index, 1);
+        assert!((x, y) != (3, 2) && (x, y) != (1, 1));
+        assert!(map.can_walk(x, y));
+        assert!((x - 3).abs() <= 1 && (y - 2).abs() <= 1);
+
+        // the item in the rock is taken to the closest floor
+        assert_eq!(repairs.moved[1], (3, (1, 1)));
+    }
+
+    #[test]
+    fn check_level_fails_without_a_way_down() {
+        let mut map = Map::from_rows(&["#####", "#...#", "#####"]);
+        assert!(check_level(&mut map, (1, 1), &[]).is_err());
+    }
+}
@@ -0,0 +1,167 @@
+use std::cell::Cell;
+use std::rc::Rc;
+
+use tcod;
+
+use messages;
+use screen::Screen;
+use state_machine::{Event, Layer, MouseButton, State, Transition};
+
+/// Where a menu writes the index of the chosen option, read back by the state that pushed it
+pub type Choice = Rc<Cell<Option<usize>>>;
+
+pub fn choice() -> Choice {
+    Rc::new(Cell::new(None))
+}
+
+/// Options shown one per row after their letter, picked with that letter or a click and highlighted
+/// under the mouse. The states listing options draw them inside their own frame.
+pub struct OptionList {
+    labels: Vec<String>,
+    width: i32,
+    /// top left corner of the options, only known once rendered
+    origin: Cell<(i32, i32)>,
+    hovered: Option<usize>,
+}
+
+impl OptionList {
+    pub fn new(labels: Vec<String>, width: i32) -> OptionList {
+        assert!(labels.len() <= 26, "cannot have more than 26 options");
+        OptionList {
+            labels: labels,
+            width: width,
+            origin: Cell::new((0, 0)),
+            hovered: None,
+        }
+    }
+
+    /// Rows taken by the options
+    pub fn height(&self) -> i32 {
+        self.labels.len() as i32
+    }
+
+    /// Draws the options with the first one at (x, y)
+    pub fn render(&self, screen: &mut Screen, x: i32, y: i32) {
+        self.origin.set((x, y));
+        for (index, label) in self.labels.iter().enumerate() {
+            let letter = (b'a' + index as u8) as char;
+            let row = y + index as i32;
+            if self.hovered == Some(index) {
+                screen.fill(x, row, self.width, 1, tcod::colors::DARKER_GREY);
+            }
+            screen.print(x, row, &format!("({}) {}", letter, label), tcod::colors::WHITE);
+        }
+    }
+
+    /// The option the event picks, if any. Mouse moves only move the highlight.
+    pub fn picked(&mut self, event: &Event) -> Option<usize> {
+        match *event {
+            Event::Key(key) => {
+                if !key.printable.is_ascii_lowercase() {
+                    return None;
+                }
+                let index = key.printable as usize - 'a' as usize;
+                if index < self.labels.len() {
+                    Some(index)
+                } else {
+                    None
+                }
+            }
+            Event::MouseMove(x, y) => {
+                self.hovered = self.option_at(x, y);
+                None
+            }
+            Event::MouseClick(x, y, MouseButton::Left) => self.option_at(x, y),
+            Event::MouseClick(..) => None,
+        }
+    }
+
+    /// Option shown at a given screen position
+    fn option_at(&self, x: i32, y: i32) -> Option<usize> {
+        let (left, top) = self.origin.get();
+        if x < left || x >= left + self.width || y < top || y >= top + self.height() {
+            return None;
+        }
+        Some((y - top) as usize)
+    }
+}
+
+/// A list of options picked with the letter keys or the mouse, popped as soon as one is chosen or on
+/// escape
+pub struct Menu {
+    /// lines above the options
+    header: Vec<String>,
+    options: OptionList,
+    width: i32,
+    choice: Choice,
+    cancelable: bool,
+}
+
+impl Menu {
+    pub fn new<T: Into<String>>(title: T, options: Vec<String>, width: i32, choice: Choice) -> Menu {
+        choice.set(None);
+        let title = title.into();
+        Menu {
+            header: if title.is_empty() {
+                Vec::new()
+            } else {
+                messages::wrap(&title, width as usize)
+            },
+            options: OptionList::new(options, width),
+            width: width,
+            choice: choice,
+            cancelable: true,
+        }
+    }
+
+    /// Keeps escape from closing the menu, an option has to be picked
+    pub fn forced(mut self) -> Menu {
+        self.cancelable = false;
+        self
+    }
+
+    /// Shows these lines above the options as they are, in place of the wrapped title
+    pub fn with_header(mut self, lines: Vec<String>) -> Menu {
+        self.header = lines;
+        self
+    }
+
+    fn pick(&mut self, index: usize) -> Transition {
+        self.choice.set(Some(index));
+        Transition::Pop
+    }
+}
+
+impl State for Menu {
+    fn render(&self, screen: &mut Screen) {
+        let header_height = if self.header.is_empty() { 0 } else { self.header.len() as i32 + 1 };
+        let height = self.options.height() + header_height;
+
+        let x = screen.width() / 2 - self.width / 2;
+        let y = screen.height() / 2 - height / 2;
+        screen.fill(x, y, self.width, height, tcod::colors::BLACK);
+        for (i, line) in self.header.iter().enumerate() {
+            screen.print(x, y + i as i32, line, tcod::colors::WHITE);
+        }
+        self.options.render(screen, x, y + header_height);
+    }
+
+    fn layer(&self) -> Layer {
+        Layer::Transparent
+    }
+
+    fn update(&mut self) -> Transition {
+        Transition::None
+    }
+
+    fn handle_event(&mut self, event: Event) -> Transition {
+        if let Some(index) = self.options.picked(&event) {
+            return self.pick(index);
+        }
+        match event {
+            Event::Key(key) if key.code == tcod::input::KeyCode::Escape && self.cancelable => Transition::Pop,
+            Event::MouseClick(_, _, MouseButton::Right) if self.cancelable => Transition::Pop,
+            _ => Transition::None,
+        }
+    }
+}
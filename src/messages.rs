@@ -0,0 +1,169 @@
+use std;
+use std::sync::{Arc, Mutex};
+
+use tcod;
+use tcod::colors::Color;
+
+use observer;
+use screen::Screen;
+use state_machine::{Event, MouseButton, State, Transition};
+
+const MAX_HISTORY: usize = 500;
+
+struct Message {
+    text: String,
+    color: Color,
+    count: u32,
+}
+
+impl Message {
+    fn line(&self) -> String {
+        if self.count > 1 {
+            format!("{} (x{})", self.text, self.count)
+        } else {
+            self.text.clone()
+        }
+    }
+}
+
+/// Every message the game logged, fed by the observer dispatcher
+pub struct MessageLog {
+    messages: Vec<Message>,
+}
+
+impl MessageLog {
+    pub fn new() -> MessageLog {
+        MessageLog {
+            messages: Vec::new(),
+        }
+    }
+
+    /// Adds a message, the same line logged twice in a row is merged into one
+    pub fn add<T: Into<String>>(&mut self, text: T, color: Color) {
+        let text = text.into();
+        if let Some(last) = self.messages.last_mut() {
+            if last.text == text && last.color == color {
+                last.count += 1;
+                return;
+            }
+        }
+        self.messages.push(Message {
+            text: text,
+            color: color,
+            count: 1,
+        });
+        if self.messages.len() > MAX_HISTORY {
+            self.messages.remove(0);
+        }
+    }
+
+    /// Wrapped lines of every message, oldest first
+    fn lines(&self, width: usize) -> Vec<(String, Color)> {
+        self.messages
+            .iter()
+            .flat_map(|m| {
+                let color = m.color;
+                wrap(&m.line(), width).into_iter().map(move |l| (l, color))
+            })
+            .collect()
+    }
+
+    /// Draws the last messages that fit in the given rectangle, `skip` lines up from the newest one
+    pub fn render(&self, con: &mut Screen, x: i32, y: i32, width: i32, height: i32, skip: usize) {
+        let lines = self.lines(width as usize);
+        let skip = std::cmp::min(skip, lines.len().saturating_sub(height as usize));
+        let end = lines.len() - skip;
+        let start = end.saturating_sub(height as usize);
+        for (i, &(ref line, color)) in lines[start..end].iter().enumerate() {
+            con.print(x, y + i as i32, line, color);
+        }
+    }
+}
+
+impl observer::Listener for MessageLog {
+    fn notify(&mut self, event: &observer::Event) {
+        if let observer::Event::Log(_, ref text, color) = *event {
+            self.add(text.as_str(), color);
+        }
+    }
+}
+
+/// Greedy word wrap, words longer than a line are cut
+pub fn wrap(text: &str, width: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut current = String::new();
+    for word in text.split_whitespace() {
+        let mut word = word.to_string();
+        while word.chars().count() > width {
+            if !current.is_empty() {
+                lines.push(std::mem::replace(&mut current, String::new()));
+            }
+            let rest = word.chars().skip(width).collect::<String>();
+            lines.push(word.chars().take(width).collect());
+            word = rest;
+        }
+        if current.is_empty() {
+            current = word;
+        } else if current.chars().count() + 1 + word.chars().count() <= width {
+            current.push(' ');
+            current.push_str(&word);
+        } else {
+            lines.push(std::mem::replace(&mut current, word));
+        }
+    }
+    if !current.is_empty() || lines.is_empty() {
+        lines.push(current);
+    }
+    lines
+}
+
+/// Full screen view of the message log that can be scrolled back
+pub struct MessageHistory {
+    log: Arc<Mutex<MessageLog>>,
+    scroll: usize,
+}
+
+impl MessageHistory {
+    pub fn new(log: Arc<Mutex<MessageLog>>) -> MessageHistory {
+        MessageHistory { log: log, scroll: 0 }
+    }
+}
+
+impl State for MessageHistory {
+    fn render(&self, screen: &mut Screen) {
+        let (width, height) = (screen.width(), screen.height());
+        screen.clear();
+        screen.print(
+            1,
+            0,
+            "Message history (up/down to scroll, escape to close)",
+            tcod::colors::WHITE,
+        );
+        self.log
+            .lock()
+            .unwrap()
+            .render(screen, 1, 2, width - 2, height - 3, self.scroll);
+    }
+
+    fn update(&mut self) -> Transition {
+        Transition::None
+    }
+
+    fn handle_event(&mut self, event: Event) -> Transition {
+        use tcod::input::KeyCode;
+
+        match event {
+            Event::Key(key) => match key.code {
+                KeyCode::Up => self.scroll += 1,
+                KeyCode::PageUp => self.scroll += 10,
+                KeyCode::Down => self.scroll = self.scroll.saturating_sub(1),
+                KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(10),
+                KeyCode::Escape => return Transition::Pop,
+                _ => {}
+            },
+            Event::MouseClick(_, _, MouseButton::Right) => return Transition::Pop,
+            _ => {}
+        }
+        Transition::None
+    }
+}
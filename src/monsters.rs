@@ -0,0 +1,289 @@
+use std;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::BufReader;
+
+use serde_json;
+use tcod;
+use tcod::colors::Color;
+
+use components::{EffectKind, LightSource, NORMAL_SPEED};
+use serde_color;
+
+pub const MONSTERS_FILE: &str = "data/monsters.json";
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Corpse {
+    pub glyph: char,
+    #[serde(with = "serde_color")]
+    pub color: Color,
+}
+
+/// A status effect the monster inflicts when its attacks hurt
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OnHit {
+    pub effect: EffectKind,
+    pub turns: i32,
+    #[serde(default)]
+    pub power: i32,
+}
+
+/// Everything needed to spawn a kind of monster
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MonsterTemplate {
+    pub id: String,
+    pub name: String,
+    pub glyph: char,
+    #[serde(with = "serde_color")]
+    pub color: Color,
+    pub hp: i32,
+    pub attack: i32,
+    pub defense: i32,
+    /// experience earned by the player for killing it
+    pub xp: i32,
+    pub corpse: Corpse,
+    /// relative odds of being picked among the monsters allowed on a floor
+    pub spawn_weight: i32,
+    pub min_depth: i32,
+    /// deepest floor the monster appears on, no limit if missing
+    #[serde(default)]
+    pub max_depth: Option<i32>,
+    #[serde(default)]
+    pub on_hit: Option<OnHit>,
+    /// energy gained every tick, `NORMAL_SPEED` if missing
+    #[serde(default = "normal_speed")]
+    pub speed: i32,
+    /// the glow around the monster while it lives, if any
+    #[serde(default)]
+    pub light: Option<LightSource>,
+}
+
+fn normal_speed() -> i32 {
+    NORMAL_SPEED
+}
+
+impl MonsterTemplate {
+    fn spawns_at(&self, depth: i32) -> bool {
+        depth >= self.min_depth && self.max_depth.map_or(true, |max| depth <= max)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MonsterFile {
+    monsters: Vec<MonsterTemplate>,
+}
+
+/// A monster file that could not be read or that holds invalid values
+#[derive(Debug)]
+pub struct MonsterError {
+    pub file: String,
+    /// path of the faulty field, like `monsters[1].hp`
+    pub field: Option<String>,
+    pub message: String,
+}
+
+impl std::fmt::Display for MonsterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.field {
+            Some(ref field) => write!(f, "{}: {}: {}", self.file, field, self.message),
+            None => write!(f, "{}: {}", self.file, self.message),
+        }
+    }
+}
+
+/// Every monster template, indexed by id
+#[derive(Clone)]
+pub struct MonsterBook {
+    templates: Vec<MonsterTemplate>,
+}
+
+impl MonsterBook {
+    pub fn load(path: &str) -> Result<MonsterBook, MonsterError> {
+        let file = File::open(path).map_err(|e| MonsterError {
+            file: path.into(),
+            field: None,
+            message: e.to_string(),
+        })?;
+        MonsterBook::read(path, BufReader::new(file))
+    }
+
+    /// Parses and validates monster templates, `path` only names the file in errors
+    fn read<R: std::io::Read>(path: &str, reader: R) -> Result<MonsterBook, MonsterError> {
+        let error = |field: Option<String>, message: String| MonsterError {
+            file: path.into(),
+            field: field,
+            message: message,
+        };
+
+        let data: MonsterFile = serde_json::from_reader(reader).map_err(|e| error(None, e.to_string()))?;
+
+        let mut ids = HashSet::new();
+        for (i, monster) in data.monsters.iter().enumerate() {
+            let field = |name: &str| Some(format!("monsters[{}].{}", i, name));
+            if monster.id.is_empty() {
+                return Err(error(field("id"), "must not be empty".into()));
+            }
+            if !ids.insert(monster.id.clone()) {
+                return Err(error(field("id"), format!("`{}` is already used by another monster", monster.id)));
+            }
+            if monster.name.is_empty() {
+                return Err(error(field("name"), "must not be empty".into()));
+            }
+            if monster.hp <= 0 {
+                return Err(error(field("hp"), format!("must be positive, got {}", monster.hp)));
+            }
+            if monster.attack < 0 {
+                return Err(error(field("attack"), format!("must not be negative, got {}", monster.attack)));
+            }
+            if monster.defense < 0 {
+                return Err(error(field("defense"), format!("must not be negative, got {}", monster.defense)));
+            }
+            if monster.xp < 0 {
+                return Err(error(field("xp"), format!("must not be negative, got {}", monster.xp)));
+            }
+            if monster.spawn_weight < 0 {
+                return Err(error(field("spawn_weight"), format!("must not be negative, got {}", monster.spawn_weight)));
+            }
+            if monster.min_depth < 1 {
+                return Err(error(field("min_depth"), format!("floors start at 1, got {}", monster.min_depth)));
+            }
+            if let Some(max_depth) = monster.max_depth {
+                if max_depth < monster.min_depth {
+                    return Err(error(
+                        field("max_depth"),
+                        format!("{} is above min_depth {}", max_depth, monster.min_depth),
+                    ));
+                }
+            }
+            if monster.speed <= 0 {
+                return Err(error(field("speed"), format!("must be positive, got {}", monster.speed)));
+            }
+            if let Some(on_hit) = monster.on_hit {
+                if on_hit.turns <= 0 {
+                    return Err(error(field("on_hit.turns"), format!("must be positive, got {}", on_hit.turns)));
+                }
+                if on_hit.power < 0 {
+                    return Err(error(field("on_hit.power"), format!("must not be negative, got {}", on_hit.power)));
+                }
+            }
+            if let Some(light) = monster.light {
+                if light.radius <= 0 {
+                    return Err(error(field("light.radius"), format!("must be positive, got {}", light.radius)));
+                }
+                if light.falloff <= 0.0 {
+                    return Err(error(field("light.falloff"), format!("must be positive, got {}", light.falloff)));
+                }
+            }
+        }
+
+        Ok(MonsterBook {
+            templates: data.monsters,
+        })
+    }
+
+    pub fn get(&self, id: &str) -> Option<&MonsterTemplate> {
+        self.templates.iter().find(|t| t.id == id)
+    }
+
+    /// Draws a monster allowed at `depth`, weighted by the spawn weights
+    pub fn pick(&self, rng: &mut tcod::random::Rng, depth: i32) -> Option<&MonsterTemplate> {
+        let candidates: Vec<&MonsterTemplate> = self
+            .templates
+            .iter()
+            .filter(|t| t.spawns_at(depth) && t.spawn_weight > 0)
+            .collect();
+        let total: i32 = candidates.iter().map(|t| t.spawn_weight).sum();
+        if total == 0 {
+            return None;
+        }
+        let mut roll = rng.get_int(0, total - 1);
+        for template in candidates {
+            if roll < template.spawn_weight {
+                return Some(template);
+            }
+            roll -= template.spawn_weight;
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    const RAT: &str = r#"{
+        "id": "rat",
+        "name": "Rat",
+        "glyph": "r",
+        "color": [127, 101, 63],
+        "hp": 3,
+        "attack": 1,
+        "defense": 0,
+        "xp": 5,
+        "corpse": { "glyph": "%", "color": [191, 0, 0] },
+        "spawn_weight": 10,
+        "min_depth": 1
+    }"#;
+
+    /// A valid monster with the fields of `overrides` added or replaced
+    fn monster(id: &str, overrides: &str) -> Value {
+        let mut monster: Value = serde_json::from_str(RAT).unwrap();
+        monster["id"] = id.into();
+        let overrides: serde_json::Map<String, Value> = serde_json::from_str(overrides).unwrap();
+        for (field, value) in overrides {
+            monster[field.as_str()] = value;
+        }
+        monster
+    }
+
+    fn read(monsters: &[Value]) -> Result<MonsterBook, MonsterError> {
+        let monsters: Vec<String> = monsters.iter().map(|m| m.to_string()).collect();
+        let text = format!("{{ \"monsters\": [{}] }}", monsters.join(", "));
+        MonsterBook::read("test.json", text.as_bytes())
+    }
+
+    #[test]
+    fn the_shipped_monsters_are_valid() {
+        let book = MonsterBook::load(MONSTERS_FILE).unwrap();
+        assert!(book.get("goblin").is_some());
+    }
+
+    #[test]
+    fn missing_optional_fields_get_their_defaults() {
+        let book = read(&[monster("rat", "{}"), monster("bat", r#"{ "speed": 150 }"#)]).unwrap();
+        assert_eq!(book.get("rat").map(|rat| (rat.speed, rat.max_depth)), Some((NORMAL_SPEED, None)));
+        assert_eq!(book.get("bat").map(|bat| bat.speed), Some(150));
+    }
+
+    #[test]
+    fn errors_name_the_file_and_the_field() {
+        let spider = monster("spider", r#"{ "on_hit": { "effect": "poison", "turns": 0, "power": 1 } }"#);
+        let error = read(&[monster("rat", "{}"), spider]).err().unwrap();
+        assert_eq!(error.field.as_ref().map(|f| f.as_str()), Some("monsters[1].on_hit.turns"));
+        assert_eq!(error.to_string(), "test.json: monsters[1].on_hit.turns: must be positive, got 0");
+
+        let error = read(&[monster("rat", r#"{ "hp": 0 }"#)]).err().unwrap();
+        assert_eq!(error.to_string(), "test.json: monsters[0].hp: must be positive, got 0");
+
+        let error = read(&[monster("rat", r#"{ "min_depth": 3, "max_depth": 2 }"#)]).err().unwrap();
+        assert_eq!(error.to_string(), "test.json: monsters[0].max_depth: 2 is above min_depth 3");
+    }
+
+    #[test]
+    fn ids_are_unique() {
+        let error = read(&[monster("rat", "{}"), monster("rat", "{}")]).err().unwrap();
+        assert_eq!(error.to_string(), "test.json: monsters[1].id: `rat` is already used by another monster");
+    }
+
+    #[test]
+    fn unknown_fields_are_refused() {
+        let error = read(&[monster("rat", r#"{ "danger": 3 }"#)]).err().unwrap();
+        assert_eq!(error.field, None);
+        assert!(error.to_string().starts_with("test.json: unknown field `danger`"));
+    }
+}
@@ -2,10 +2,14 @@ use std::sync::{Arc, Mutex};
 use std::marker::Send;
 
 use specs::Entity;
+use tcod::colors::Color;
 
 #[derive(Debug)]
 pub enum Event {
-    Log(Entity, String),
+    /// a line for the message log, about the given entity
+    Log(Entity, String, Color),
+    /// a turn was played, with the number of the turn and a hash of the resulting state
+    TurnEnded(i64, u64),
 } 
 
 pub trait Listener {
@@ -0,0 +1,350 @@
+use std;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+use map;
+
+const DEFAULT_COST: i32 = 1;
+// a flee map is the chase map inverted and scaled, so fleeing monsters prefer open space over corners
+const FLEE_FACTOR: f32 = -1.2;
+
+const CARDINALS: [(i32, i32); 4] = [(0, -1), (0, 1), (-1, 0), (1, 0)];
+const DIAGONALS: [(i32, i32); 4] = [(-1, -1), (1, -1), (-1, 1), (1, 1)];
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Node {
+    cost: i32,
+    pos: (i32, i32),
+}
+
+// BinaryHeap is a max-heap, we want the cheapest node first
+impl Ord for Node {
+    fn cmp(&self, other: &Node) -> Ordering {
+        other.cost.cmp(&self.cost).then_with(|| self.pos.cmp(&other.pos))
+    }
+}
+
+impl PartialOrd for Node {
+    fn partial_cmp(&self, other: &Node) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Searches paths over the tiles of a `map::Map`.
+///
+/// Walls come from the map itself, closed doors count as open since they get opened on the way.
+/// Everything else (blocking entities, expensive tiles) is layered on top by the caller.
+pub struct Pathfinder<'m> {
+    map: &'m map::Map,
+    obstacles: HashSet<(i32, i32)>,
+    costs: HashMap<(i32, i32), i32>,
+    diagonals: bool,
+}
+
+impl<'m> Pathfinder<'m> {
+    pub fn new(map: &'m map::Map) -> Pathfinder<'m> {
+        Pathfinder {
+            map: map,
+            obstacles: HashSet::new(),
+            costs: HashMap::new(),
+            diagonals: true,
+        }
+    }
+
+    /// Allows or forbids diagonal steps (allowed by default)
+    pub fn diagonals(mut self, allowed: bool) -> Self {
+        self.diagonals = allowed;
+        self
+    }
+
+    /// Marks tiles as impassable, typically the positions of blocking entities
+    pub fn obstacles<I>(mut self, obstacles: I) -> Self
+    where
+        I: IntoIterator<Item = (i32, i32)>,
+    {
+        self.obstacles.extend(obstacles);
+        self
+    }
+
+    /// Overrides the cost of entering a tile
+    pub fn cost(mut self, x: i32, y: i32, cost: i32) -> Self {
+        self.costs.insert((x, y), cost);
+        self
+    }
+
+    fn passable(&self, x: i32, y: i32) -> bool {
+        (self.map.can_walk(x, y) || self.map.can_open(x, y)) && !self.obstacles.contains(&(x, y))
+    }
+
+    fn step_cost(&self, x: i32, y: i32) -> i32 {
+        *self.costs.get(&(x, y)).unwrap_or(&DEFAULT_COST)
+    }
+
+    fn directions(&self) -> Vec<(i32, i32)> {
+        let mut directions = CARDINALS.to_vec();
+        if self.diagonals {
+            directions.extend_from_slice(&DIAGONALS);
+        }
+        directions
+    }
+
+    fn heuristic(&self, from: (i32, i32), to: (i32, i32)) -> i32 {
+        let dx = (from.0 - to.0).abs();
+        let dy = (from.1 - to.1).abs();
+        if self.diagonals {
+            std::cmp::max(dx, dy)
+        } else {
+            dx + dy
+        }
+    }
+
+    /// Finds the cheapest path between two tiles with A*.
+    ///
+    /// The returned path excludes `from` and ends on `to`. The goal may be an obstacle
+    /// (the player a monster is chasing for instance), but no other tile of the path will be.
+    pub fn astar(&self, from: (i32, i32), to: (i32, i32)) -> Option<Vec<(i32, i32)>> {
+        let directions = self.directions();
+        let mut open = BinaryHeap::new();
+        let mut came_from = HashMap::new();
+        let mut best = HashMap::new();
+
+        best.insert(from, 0);
+        open.push(Node { cost: self.heuristic(from, to), pos: from });
+
+        while let Some(Node { pos, .. }) = open.pop() {
+            if pos == to {
+                let mut path = vec![pos];
+                let mut current = pos;
+                while let Some(&previous) = came_from.get(&current) {
+                    if previous == from {
+                        break;
+                    }
+                    path.push(previous);
+                    current = previous;
+                }
+                path.reverse();
+                return Some(path);
+            }
+            let cost = best[&pos];
+            for &(dx, dy) in directions.iter() {
+                let next = (pos.0 + dx, pos.1 + dy);
+                if next != to && !self.passable(next.0, next.1) {
+                    continue;
+                }
+                if next == to && !self.map.can_walk(next.0, next.1) {
+                    continue;
+                }
+                let next_cost = cost + self.step_cost(next.0, next.1);
+                if best.get(&next).map_or(true, |&c| next_cost < c) {
+                    best.insert(next, next_cost);
+                    came_from.insert(next, pos);
+                    open.push(Node {
+                        cost: next_cost + self.heuristic(next, to),
+                        pos: next,
+                    });
+                }
+            }
+        }
+        None
+    }
+
+    /// Builds a distance map where every reachable tile holds the cost to the closest source
+    pub fn dijkstra(&self, sources: &[(i32, i32)]) -> DistanceMap {
+        let seeds: Vec<((i32, i32), i32)> = sources.iter().map(|&s| (s, 0)).collect();
+        self.dijkstra_seeded(&seeds)
+    }
+
+    /// Same as `dijkstra` but every source starts with its own value, which allows
+    /// goals of different desirability in the same map
+    pub fn dijkstra_seeded(&self, seeds: &[((i32, i32), i32)]) -> DistanceMap {
+        let mut distances = DistanceMap::new(self.map.width(), self.map.height(), self.diagonals);
+        let directions = self.directions();
+        let mut open = BinaryHeap::new();
+
+        for &(pos, value) in seeds {
+            if distances.get(pos.0, pos.1).map_or(true, |v| value < v) {
+                distances.set(pos.0, pos.1, value);
+                open.push(Node { cost: value, pos: pos });
+            }
+        }
+
+        while let Some(Node { cost, pos }) = open.pop() {
+            if distances.get(pos.0, pos.1).map_or(false, |v| cost > v) {
+                continue; // stale entry
+            }
+            for &(dx, dy) in directions.iter() {
+                let next = (pos.0 + dx, pos.1 + dy);
+                if !self.passable(next.0, next.1) {
+                    continue;
+                }
+                let next_cost = cost + self.step_cost(next.0, next.1);
+                if distances.get(next.0, next.1).map_or(true, |v| next_cost < v) {
+                    distances.set(next.0, next.1, next_cost);
+                    open.push(Node { cost: next_cost, pos: next });
+                }
+            }
+        }
+        distances
+    }
+
+    /// Turns a chase map into a flee map: walking downhill on the result moves away
+    /// from the sources of `chase`, preferring escape routes over dead ends
+    pub fn flee(&self, chase: &DistanceMap) -> DistanceMap {
+        let mut seeds = Vec::new();
+        for y in 0..chase.height {
+            for x in 0..chase.width {
+                if let Some(v) = chase.get(x, y) {
+                    seeds.push(((x, y), (v as f32 * FLEE_FACTOR) as i32));
+                }
+            }
+        }
+        self.dijkstra_seeded(&seeds)
+    }
+}
+
+/// Result of a Dijkstra search, `None` marks unreachable tiles
+pub struct DistanceMap {
+    width: i32,
+    height: i32,
+    diagonals: bool,
+    values: Vec<Option<i32>>,
+}
+
+impl DistanceMap {
+    fn new(width: i32, height: i32, diagonals: bool) -> DistanceMap {
+        DistanceMap {
+            width: width,
+            height: height,
+            diagonals: diagonals,
+            values: vec![None; (width * height) as usize],
+        }
+    }
+
+    fn in_bounds(&self, x: i32, y: i32) -> bool {
+        x >= 0 && x < self.width && y >= 0 && y < self.height
+    }
+
+    fn set(&mut self, x: i32, y: i32, value: i32) {
+        if self.in_bounds(x, y) {
+            self.values[(x + y * self.width) as usize] = Some(value);
+        }
+    }
+
+    pub fn get(&self, x: i32, y: i32) -> Option<i32> {
+        if self.in_bounds(x, y) {
+            self.values[(x + y * self.width) as usize]
+        } else {
+            None
+        }
+    }
+
+    /// Returns the neighbour of (x, y) with the lowest value, if it is lower than (x, y) itself
+    pub fn downhill(&self, x: i32, y: i32) -> Option<(i32, i32)> {
+        let mut directions = CARDINALS.to_vec();
+        if self.diagonals {
+            directions.extend_from_slice(&DIAGONALS);
+        }
+        let mut best = self.get(x, y).map(|v| ((x, y), v));
+        for (dx, dy) in directions {
+            if let Some(v) = self.get(x + dx, y + dy) {
+                if best.map_or(true, |(_, b)| v < b) {
+                    best = Some(((x + dx, y + dy), v));
+                }
+            }
+        }
+        match best {
+            Some((pos, _)) if pos != (x, y) => Some(pos),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use map::Map;
+
+    fn corridor() -> Map {
+        Map::from_rows(&["###########", "#.........#", "###########"])
+    }
+
+    #[test]
+    fn astar_goes_around_a_wall() {
+        let map = Map::from_rows(&[
+            "#########",
+            "#...#...#",
+            "#...#...#",
+            "#.......#",
+            "#########",
+        ]);
+        let path = Pathfinder::new(&map).astar((1, 1), (7, 1)).unwrap();
+        assert_eq!(path.len(), 6);
+        assert_eq!(path.last(), Some(&(7, 1)));
+        assert!(path.contains(&(4, 3)));
+        assert!(path.iter().all(|&(x, y)| map.can_walk(x, y)));
+    }
+
+    #[test]
+    fn astar_goes_around_a_blocking_entity() {
+        let map = Map::from_rows(&[
+            "#######",
+            "#.....#",
+            "#.###.#",
+            "#.....#",
+            "#######",
+        ]);
+        let path = Pathfinder::new(&map)
+            .obstacles(vec![(3, 1)])
+            .astar((1, 1), (5, 1))
+            .unwrap();
+        assert_eq!(path.last(), Some(&(5, 1)));
+        assert!(!path.contains(&(3, 1)));
+        assert!(path.contains(&(3, 3)));
+    }
+
+    #[test]
+    fn astar_gives_up_on_an_unreachable_goal() {
+        let map = Map::from_rows(&[
+            "#######",
+            "#..#..#",
+            "#..#..#",
+            "#######",
+        ]);
+        assert_eq!(Pathfinder::new(&map).astar((1, 1), (5, 2)), None);
+    }
+
+    #[test]
+    fn dijkstra_takes_the_cheapest_source() {
+        let map = corridor();
+        let distances = Pathfinder::new(&map)
+            .cost(3, 1, 10)
+            .dijkstra(&[(1, 1), (9, 1)]);
+        assert_eq!(distances.get(1, 1), Some(0));
+        assert_eq!(distances.get(9, 1), Some(0));
+        assert_eq!(distances.get(2, 1), Some(1));
+        assert_eq!(distances.get(3, 1), Some(11));
+        // cheaper to come all the way from the right than through the expensive tile
+        assert_eq!(distances.get(4, 1), Some(5));
+        assert_eq!(distances.get(5, 1), Some(4));
+        assert_eq!(distances.get(0, 1), None);
+    }
+
+    #[test]
+    fn downhill_on_a_chase_map_leads_to_the_source() {
+        let map = corridor();
+        let chase = Pathfinder::new(&map).dijkstra(&[(1, 1)]);
+        assert_eq!(chase.downhill(4, 1), Some((3, 1)));
+        assert_eq!(chase.downhill(1, 1), None);
+    }
+
+    #[test]
+    fn downhill_on_a_flee_map_leads_away_from_the_source() {
+        let map = corridor();
+        let finder = Pathfinder::new(&map);
+        let flee = finder.flee(&finder.dijkstra(&[(1, 1)]));
+        assert_eq!(flee.downhill(4, 1), Some((5, 1)));
+        assert_eq!(flee.downhill(8, 1), Some((9, 1)));
+        // nowhere further to run
+        assert_eq!(flee.downhill(9, 1), None);
+    }
+}
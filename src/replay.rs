@@ -0,0 +1,306 @@
+use std;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+
+use serde_json;
+use tcod;
+
+use input;
+use keymap;
+use observer;
+use state_machine::{Event, MouseButton};
+
+pub const REPLAY_FILE: &str = "last_game.replay";
+
+/// A `state_machine::Event` in a form that can be written to a file
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum RecordedEvent {
+    Key {
+        code: String,
+        printable: char,
+        shift: bool,
+        ctrl: bool,
+        alt: bool,
+    },
+    MouseMove {
+        x: i32,
+        y: i32,
+    },
+    MouseClick {
+        x: i32,
+        y: i32,
+        button: MouseButton,
+    },
+}
+
+impl RecordedEvent {
+    pub fn new(event: &Event) -> RecordedEvent {
+        match *event {
+            Event::Key(key) => RecordedEvent::Key {
+                code: input::key_code_name(key.code).into(),
+                printable: key.printable,
+                shift: key.shift,
+                ctrl: key.ctrl,
+                alt: key.alt,
+            },
+            Event::MouseMove(x, y) => RecordedEvent::MouseMove { x: x, y: y },
+            Event::MouseClick(x, y, button) => RecordedEvent::MouseClick {
+                x: x,
+                y: y,
+                button: button,
+            },
+        }
+    }
+
+    pub fn event(&self) -> Option<Event> {
+        match *self {
+            RecordedEvent::Key {
+                ref code,
+                printable,
+                shift,
+                ctrl,
+                alt,
+            } => input::key_code_from_name(code).map(|code| {
+                Event::Key(tcod::input::Key {
+                    code: code,
+                    printable: printable,
+                    pressed: true,
+                    shift: shift,
+                    left_ctrl: ctrl,
+                    ctrl: ctrl,
+                    left_alt: alt,
+                    alt: alt,
+                    ..Default::default()
+                })
+            }),
+            RecordedEvent::MouseMove { x, y } => Some(Event::MouseMove(x, y)),
+            RecordedEvent::MouseClick { x, y, button } => Some(Event::MouseClick(x, y, button)),
+        }
+    }
+}
+
+/// Hash of the game state at the end of a turn
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TurnHash {
+    pub turn: i64,
+    /// number of events handled when the turn ended
+    pub events: usize,
+    pub hash: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Replay {
+    pub seed: u32,
+    /// keys of every action while recording, the events are raw keys. Default bindings if missing.
+    #[serde(default)]
+    pub keys: BTreeMap<String, Vec<String>>,
+    pub events: Vec<RecordedEvent>,
+    pub turns: Vec<TurnHash>,
+}
+
+impl Replay {
+    pub fn load(path: &str) -> std::io::Result<Replay> {
+        let file = File::open(path)?;
+        let replay = serde_json::from_reader(BufReader::new(file))?;
+        Ok(replay)
+    }
+
+    pub fn save(&self, path: &str) -> std::io::Result<()> {
+        let file = File::create(path)?;
+        serde_json::to_writer(BufWriter::new(file), self)?;
+        Ok(())
+    }
+}
+
+/// Writes down every event fed to the state machine and the hash of every turn they lead to
+pub struct Recorder {
+    replay: Replay,
+}
+
+impl Recorder {
+    pub fn new(seed: u32, keymap: &keymap::KeyMap) -> Recorder {
+        Recorder {
+            replay: Replay {
+                seed: seed,
+                keys: keymap.names(),
+                events: Vec::new(),
+                turns: Vec::new(),
+            },
+        }
+    }
+
+    pub fn record(&mut self, event: &Event) {
+        self.replay.events.push(RecordedEvent::new(event));
+    }
+
+    pub fn save(&self, path: &str) -> std::io::Result<()> {
+        self.replay.save(path)
+    }
+}
+
+impl observer::Listener for Recorder {
+    fn notify(&mut self, event: &observer::Event) {
+        if let observer::Event::TurnEnded(turn, hash) = *event {
+            let events = self.replay.events.len();
+            self.replay.turns.push(TurnHash {
+                turn: turn,
+                events: events,
+                hash: hash,
+            });
+        }
+    }
+}
+
+/// First turn where a replay did not end up in the recorded state
+#[derive(Debug)]
+pub struct Divergence {
+    pub expected: Option<TurnHash>,
+    pub found: TurnHash,
+}
+
+impl std::fmt::Display for Divergence {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.expected {
+            Some(expected) => write!(
+                f,
+                "replay diverged at turn {} after {} events: expected state {:016x}, got {:016x} (turn {})",
+                expected.turn, expected.events, expected.hash, self.found.hash, self.found.turn
+            ),
+            None => write!(
+                f,
+                "replay played turn {} after {} events, which was never recorded",
+                self.found.turn, self.found.events
+            ),
+        }
+    }
+}
+
+/// Checks the turns of a replayed game against the recorded ones
+pub struct Verifier {
+    expected: Vec<TurnHash>,
+    played: usize,
+    events: usize,
+    divergence: Option<Divergence>,
+}
+
+impl Verifier {
+    pub fn new(expected: Vec<TurnHash>) -> Verifier {
+        Verifier {
+            expected: expected,
+            played: 0,
+            events: 0,
+            divergence: None,
+        }
+    }
+
+    /// To be called before every replayed event, so turns know how many events led to them
+    pub fn event_handled(&mut self) {
+        self.events += 1;
+    }
+
+    pub fn divergence(&self) -> Option<&Divergence> {
+        self.divergence.as_ref()
+    }
+
+    /// Number of recorded turns that were not replayed
+    pub fn missing_turns(&self) -> usize {
+        self.expected.len().saturating_sub(self.played)
+    }
+}
+
+impl observer::Listener for Verifier {
+    fn notify(&mut self, event: &observer::Event) {
+        if let observer::Event::TurnEnded(turn, hash) = *event {
+            if self.divergence.is_some() {
+                return; // only the first one is meaningful
+            }
+            let found = TurnHash {
+                turn: turn,
+                events: self.events,
+                hash: hash,
+            };
+            let expected = self.expected.get(self.played).cloned();
+            if expected != Some(found) {
+                self.divergence = Some(Divergence {
+                    expected: expected,
+                    found: found,
+                });
+            }
+            self.played += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use observer::Listener;
+
+    fn events() -> Vec<Event> {
+        let key = |c| {
+            Event::Key(tcod::input::Key {
+                code: tcod::input::KeyCode::Char,
+                printable: c,
+                pressed: true,
+                ..Default::default()
+            })
+        };
+        vec![key('k'), Event::MouseMove(3, 4), key('.'), Event::MouseClick(5, 6, MouseButton::Left)]
+    }
+
+    /// Records one turn per event, ending in the state hashes given
+    fn record(hashes: &[u64]) -> Replay {
+        let keymap = keymap::KeyMap::from_names(&BTreeMap::new()).unwrap();
+        let mut recorder = Recorder::new(42, &keymap);
+        for (turn, (event, &hash)) in events().iter().zip(hashes).enumerate() {
+            recorder.record(event);
+            recorder.notify(&observer::Event::TurnEnded(turn as i64 + 1, hash));
+        }
+        recorder.replay
+    }
+
+    fn verify(replay: &Replay, hashes: &[u64]) -> Verifier {
+        let mut verifier = Verifier::new(replay.turns.clone());
+        for (turn, &hash) in hashes.iter().enumerate() {
+            verifier.event_handled();
+            verifier.notify(&observer::Event::TurnEnded(turn as i64 + 1, hash));
+        }
+        verifier
+    }
+
+    #[test]
+    fn recorded_events_read_back() {
+        let replay = record(&[1, 2, 3, 4]);
+        assert_eq!(replay.events.len(), 4);
+        for (recorded, event) in replay.events.iter().zip(events()) {
+            assert_eq!(recorded.event().map(|e| RecordedEvent::new(&e)), Some(RecordedEvent::new(&event)));
+        }
+    }
+
+    #[test]
+    fn identical_turns_are_accepted() {
+        let replay = record(&[1, 2, 3, 4]);
+        let verifier = verify(&replay, &[1, 2, 3, 4]);
+        assert!(verifier.divergence().is_none());
+        assert_eq!(verifier.missing_turns(), 0);
+    }
+
+    #[test]
+    fn the_first_divergent_turn_is_reported() {
+        let replay = record(&[1, 2, 3, 4]);
+        let verifier = verify(&replay, &[1, 2, 9, 5]);
+        let divergence = verifier.divergence().unwrap();
+        assert_eq!(divergence.expected.map(|turn| (turn.turn, turn.events, turn.hash)), Some((3, 3, 3)));
+        assert_eq!(divergence.found.hash, 9);
+        assert!(divergence.to_string().contains("turn 3 after 3 events"));
+    }
+
+    #[test]
+    fn a_short_replay_misses_turns() {
+        let replay = record(&[1, 2, 3, 4]);
+        let verifier = verify(&replay, &[1, 2]);
+        assert!(verifier.divergence().is_none());
+        assert_eq!(verifier.missing_turns(), 2);
+    }
+}
@@ -0,0 +1,50 @@
+use std;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+
+use serde_json;
+
+use components;
+use map;
+
+pub const SAVE_FILE: &str = "savegame.json";
+
+/// Everything needed to bring a running game back
+#[derive(Serialize, Deserialize)]
+pub struct SaveGame {
+    /// the game's rng is reseeded with this value when saving, so a loaded game draws the same numbers
+    pub seed: u32,
+    pub turns: i64,
+    /// turns the player took, `turns` counts the ticks of the scheduler
+    #[serde(default)]
+    pub player_turns: i64,
+    pub depth: i32,
+    pub map: map::MapData,
+    /// floors the player is not on, indexed by depth - 1
+    pub floors: Vec<Option<map::MapData>>,
+    pub entities: Vec<components::EntityRecord>,
+}
+
+pub fn exists(path: &str) -> bool {
+    std::path::Path::new(path).is_file()
+}
+
+pub fn save_game(path: &str, save: &SaveGame) -> std::io::Result<()> {
+    let file = File::create(path)?;
+    serde_json::to_writer(BufWriter::new(file), save)?;
+    Ok(())
+}
+
+/// Removes the save, if there is one
+pub fn delete_save(path: &str) -> std::io::Result<()> {
+    if exists(path) {
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+pub fn load_game(path: &str) -> std::io::Result<SaveGame> {
+    let file = File::open(path)?;
+    let save = serde_json::from_reader(BufReader::new(file))?;
+    Ok(save)
+}
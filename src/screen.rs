@@ -0,0 +1,205 @@
+use tcod;
+use tcod::colors::Color;
+use tcod::Console;
+
+/// One character of a screen
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Cell {
+    pub glyph: char,
+    pub fg: Color,
+    pub bg: Color,
+}
+
+impl Cell {
+    pub fn blank() -> Cell {
+        Cell {
+            glyph: ' ',
+            fg: tcod::colors::WHITE,
+            bg: tcod::colors::BLACK,
+        }
+    }
+}
+
+/// `color` darkened by `amount`, from 0 (unchanged) to 1 (black)
+fn darken(color: Color, amount: f32) -> Color {
+    let keep = 1.0 - amount.max(0.0).min(1.0);
+    Color {
+        r: (color.r as f32 * keep) as u8,
+        g: (color.g as f32 * keep) as u8,
+        b: (color.b as f32 * keep) as u8,
+    }
+}
+
+/// Anything the game can draw on, be it the tcod window or an in-memory `Grid`.
+///
+/// Implementors only provide cell access, everything else is built on top of it.
+pub trait Screen {
+    fn width(&self) -> i32;
+    fn height(&self) -> i32;
+    fn cell(&self, x: i32, y: i32) -> Cell;
+    fn set_cell(&mut self, x: i32, y: i32, cell: Cell);
+
+    fn in_bounds(&self, x: i32, y: i32) -> bool {
+        x >= 0 && x < self.width() && y >= 0 && y < self.height()
+    }
+
+    /// Fills a rectangle with blank cells of the given background
+    fn fill(&mut self, x: i32, y: i32, width: i32, height: i32, bg: Color) {
+        for y in y..(y + height) {
+            for x in x..(x + width) {
+                if self.in_bounds(x, y) {
+                    self.set_cell(x, y, Cell { bg: bg, ..Cell::blank() });
+                }
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        let (width, height) = (self.width(), self.height());
+        self.fill(0, 0, width, height, tcod::colors::BLACK);
+    }
+
+    /// Draws a glyph, keeping the background of the cell
+    fn put_char(&mut self, x: i32, y: i32, glyph: char, fg: Color) {
+        if self.in_bounds(x, y) {
+            let cell = self.cell(x, y);
+            self.set_cell(x, y, Cell { glyph: glyph, fg: fg, ..cell });
+        }
+    }
+
+    fn set_glyph(&mut self, x: i32, y: i32, glyph: char) {
+        if self.in_bounds(x, y) {
+            let cell = self.cell(x, y);
+            self.set_cell(x, y, Cell { glyph: glyph, ..cell });
+        }
+    }
+
+    fn set_background(&mut self, x: i32, y: i32, bg: Color) {
+        if self.in_bounds(x, y) {
+            let cell = self.cell(x, y);
+            self.set_cell(x, y, Cell { bg: bg, ..cell });
+        }
+    }
+
+    /// Darkens everything already drawn, to set apart what gets drawn next
+    fn dim(&mut self, amount: f32) {
+        for y in 0..self.height() {
+            for x in 0..self.width() {
+                let cell = self.cell(x, y);
+                self.set_cell(x, y, Cell { fg: darken(cell.fg, amount), bg: darken(cell.bg, amount), ..cell });
+            }
+        }
+    }
+
+    /// Prints a single line of text, cut at the edge of the screen
+    fn print(&mut self, x: i32, y: i32, text: &str, fg: Color) {
+        for (i, glyph) in text.chars().enumerate() {
+            self.put_char(x + i as i32, y, glyph, fg);
+        }
+    }
+}
+
+impl Screen for tcod::console::Root {
+    fn width(&self) -> i32 {
+        Console::width(self)
+    }
+
+    fn height(&self) -> i32 {
+        Console::height(self)
+    }
+
+    fn cell(&self, x: i32, y: i32) -> Cell {
+        Cell {
+            glyph: self.get_char(x, y),
+            fg: self.get_char_foreground(x, y),
+            bg: self.get_char_background(x, y),
+        }
+    }
+
+    fn set_cell(&mut self, x: i32, y: i32, cell: Cell) {
+        self.put_char_ex(x, y, cell.glyph, cell.fg, cell.bg);
+    }
+}
+
+/// In-memory screen, used as the game's back buffer and to run without a window
+#[derive(Clone)]
+pub struct Grid {
+    width: i32,
+    height: i32,
+    cells: Vec<Cell>,
+}
+
+impl Grid {
+    pub fn new(width: i32, height: i32) -> Grid {
+        Grid {
+            width: width,
+            height: height,
+            cells: vec![Cell::blank(); (width * height) as usize],
+        }
+    }
+
+    /// Copies the whole grid on another screen
+    pub fn blit(&self, dest: &mut Screen) {
+        for y in 0..self.height {
+            for x in 0..self.width {
+                if dest.in_bounds(x, y) {
+                    dest.set_cell(x, y, self.cell(x, y));
+                }
+            }
+        }
+    }
+
+    /// The glyphs of a row as a string, handy to check what was printed
+    #[cfg(test)]
+    pub fn row(&self, y: i32) -> String {
+        (0..self.width).map(|x| self.cell(x, y).glyph).collect()
+    }
+}
+
+impl Screen for Grid {
+    fn width(&self) -> i32 {
+        self.width
+    }
+
+    fn height(&self) -> i32 {
+        self.height
+    }
+
+    fn cell(&self, x: i32, y: i32) -> Cell {
+        self.cells[(x + y * self.width) as usize]
+    }
+
+    fn set_cell(&mut self, x: i32, y: i32, cell: Cell) {
+        self.cells[(x + y * self.width) as usize] = cell;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn print_is_cut_at_the_edge() {
+        let mut grid = Grid::new(6, 2);
+        grid.print(2, 1, "hello", tcod::colors::RED);
+        assert_eq!(grid.row(0), "      ");
+        assert_eq!(grid.row(1), "  hell");
+        assert_eq!(grid.cell(2, 1).fg, tcod::colors::RED);
+    }
+
+    #[test]
+    fn put_char_keeps_the_background() {
+        let mut grid = Grid::new(3, 1);
+        grid.fill(0, 0, 3, 1, tcod::colors::BLUE);
+        grid.put_char(1, 0, '@', tcod::colors::WHITE);
+        assert_eq!(grid.cell(1, 0), Cell { glyph: '@', fg: tcod::colors::WHITE, bg: tcod::colors::BLUE });
+    }
+
+    #[test]
+    fn dim_darkens_both_colors() {
+        let mut grid = Grid::new(1, 1);
+        grid.set_cell(0, 0, Cell { glyph: 'x', fg: Color { r: 200, g: 100, b: 0 }, bg: Color { r: 100, g: 50, b: 20 } });
+        grid.dim(0.5);
+        assert_eq!(grid.cell(0, 0), Cell { glyph: 'x', fg: Color { r: 100, g: 50, b: 0 }, bg: Color { r: 50, g: 25, b: 10 } });
+    }
+}
@@ -0,0 +1,18 @@
+//! Lets `#[serde(with = "serde_color")]` store a `tcod::colors::Color` as an `(r, g, b)` tuple
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use tcod::colors::Color;
+
+pub fn serialize<S>(color: &Color, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    (color.r, color.g, color.b).serialize(serializer)
+}
+
+pub fn deserialize<'de, D>(deserializer: D) -> Result<Color, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let (r, g, b) = <(u8, u8, u8)>::deserialize(deserializer)?;
+    Ok(Color { r: r, g: g, b: b })
+}
@@ -1,7 +1,29 @@
 use tcod;
 
+use screen::Screen;
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub enum MouseButton {
+    Left,
+    Right
+}
+
+/// Input fed to the states, mouse positions are in console cells
 pub enum Event {
-    Key(tcod::input::Key)
+    Key(tcod::input::Key),
+    MouseMove(i32, i32),
+    MouseClick(i32, i32, MouseButton)
+}
+
+/// How a state covers the states under it on the stack
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Layer {
+    /// hides everything under it
+    Opaque,
+    /// drawn over the state under it, which shows through wherever it does not draw
+    Transparent,
+    /// a modal overlay: the state under it is darkened by the given amount, from 0 to 1, first
+    Dimmed(f32)
 }
 
 pub enum Transition {
@@ -13,7 +35,9 @@ pub enum Transition {
 }
 
 pub trait State {
-    fn render(&self, root: &mut tcod::Console);
+    fn render(&self, root: &mut Screen);
+    /// Whether the states under this one are drawn too, only the top state gets the input anyway
+    fn layer(&self) -> Layer { Layer::Opaque }
     fn update(&mut self) -> Transition;
     fn handle_event(&mut self, event: Event) -> Transition;
     fn on_start(&mut self) {}
@@ -39,10 +63,16 @@ impl StateMachine {
         self.running
     }
 
-    pub fn render(&self, root: &mut tcod::Console) {
+    /// Draws the stack bottom-up, starting from the closest opaque state to the top
+    pub fn render(&self, root: &mut Screen) {
         if self.running {
-            let state = self.states.last().unwrap();
-            state.render(root);
+            let bottom = self.states.iter().rposition(|state| state.layer() == Layer::Opaque).unwrap_or(0);
+            for state in self.states[bottom..].iter() {
+                if let Layer::Dimmed(amount) = state.layer() {
+                    root.dim(amount);
+                }
+                state.render(root);
+            }
         }
     }
 
@@ -121,7 +151,7 @@ impl StateMachine {
         }
     }
 
-    fn stop(&mut self) {
+    pub fn stop(&mut self) {
         if self.running {
             while let Some(mut state) = self.states.pop() {
                 state.on_stop();
@@ -129,4 +159,77 @@ impl StateMachine {
             self.running = false;
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use screen::Grid;
+
+    /// Prints its text on its own row, and pushes the next state on its first update
+    struct Paint {
+        text: &'static str,
+        row: i32,
+        layer: Layer,
+        next: Option<Box<State>>,
+    }
+
+    impl Paint {
+        fn new(text: &'static str, row: i32, layer: Layer, next: Option<Box<State>>) -> Box<State> {
+            Box::new(Paint { text: text, row: row, layer: layer, next: next })
+        }
+    }
+
+    impl State for Paint {
+        fn render(&self, root: &mut Screen) {
+            root.print(0, self.row, self.text, tcod::colors::WHITE);
+        }
+
+        fn layer(&self) -> Layer {
+            self.layer
+        }
+
+        fn update(&mut self) -> Transition {
+            match self.next.take() {
+                Some(state) => Transition::Push(state),
+                None => Transition::None,
+            }
+        }
+
+        fn handle_event(&mut self, _event: Event) -> Transition {
+            Transition::None
+        }
+    }
+
+    fn render_stack(states: Box<State>, depth: usize) -> Grid {
+        let mut machine = StateMachine::new(states);
+        machine.start();
+        for _ in 1..depth {
+            machine.update();
+        }
+        let mut grid = Grid::new(5, 3);
+        machine.render(&mut grid);
+        grid
+    }
+
+    #[test]
+    fn an_opaque_state_hides_the_ones_under_it() {
+        let top = Paint::new("top", 1, Layer::Opaque, None);
+        let grid = render_stack(Paint::new("base", 0, Layer::Opaque, Some(top)), 2);
+        assert_eq!(grid.row(0), "     ");
+        assert_eq!(grid.row(1), "top  ");
+    }
+
+    #[test]
+    fn overlays_are_drawn_over_the_state_under_them() {
+        let dimmed = Paint::new("over", 2, Layer::Dimmed(0.5), None);
+        let menu = Paint::new("menu", 1, Layer::Transparent, Some(dimmed));
+        let grid = render_stack(Paint::new("base", 0, Layer::Opaque, Some(menu)), 3);
+        assert_eq!(grid.row(0), "base ");
+        assert_eq!(grid.row(1), "menu ");
+        assert_eq!(grid.row(2), "over ");
+        // everything under the dimmed state got darkened before it was drawn
+        assert_eq!(grid.cell(0, 0).fg, tcod::Color { r: 127, g: 127, b: 127 });
+        assert_eq!(grid.cell(0, 2).fg, tcod::colors::WHITE);
+    }
+}
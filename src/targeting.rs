@@ -0,0 +1,142 @@
+use std::cell::Cell;
+use std::rc::Rc;
+
+use tcod;
+use tcod::colors::Color;
+
+use map;
+use screen::Screen;
+use state_machine::{Event, Layer, MouseButton, State, Transition};
+
+/// Where the targeting screen writes the confirmed tile, read back by the state that pushed it
+pub type Target = Rc<Cell<Option<(i32, i32)>>>;
+
+pub fn target() -> Target {
+    Rc::new(Cell::new(None))
+}
+
+const COLOR_PATH: Color = Color { r: 90, g: 90, b: 30 };
+const COLOR_VALID: Color = Color { r: 60, g: 120, b: 200 };
+const COLOR_INVALID: Color = Color { r: 140, g: 30, b: 30 };
+
+/// Moves a cursor over the map to pick a tile, with the keys, the mouse or by cycling through the
+/// visible monsters with Tab. Only the given tiles can be confirmed.
+pub struct Targeting {
+    origin: (i32, i32),
+    tiles: Vec<(i32, i32)>,
+    /// visible monsters and their names, closest first
+    monsters: Vec<(i32, i32, String)>,
+    cursor: (i32, i32),
+    next_monster: usize,
+    target: Target,
+}
+
+impl Targeting {
+    pub fn new(
+        origin: (i32, i32),
+        tiles: Vec<(i32, i32)>,
+        monsters: Vec<(i32, i32, String)>,
+        target: Target,
+    ) -> Targeting {
+        target.set(None);
+        let cursor = monsters.first().map_or(origin, |&(x, y, _)| (x, y));
+        Targeting {
+            origin: origin,
+            tiles: tiles,
+            next_monster: if monsters.is_empty() { 0 } else { 1 },
+            monsters: monsters,
+            cursor: cursor,
+            target: target,
+        }
+    }
+
+    fn is_valid(&self, tile: (i32, i32)) -> bool {
+        tile != self.origin && self.tiles.contains(&tile)
+    }
+
+    fn confirm(&mut self) -> Transition {
+        if self.is_valid(self.cursor) {
+            self.target.set(Some(self.cursor));
+            Transition::Pop
+        } else {
+            Transition::None
+        }
+    }
+
+    fn cycle(&mut self) {
+        if self.monsters.is_empty() {
+            return;
+        }
+        let index = self.next_monster % self.monsters.len();
+        let (x, y, _) = self.monsters[index];
+        self.cursor = (x, y);
+        self.next_monster = index + 1;
+    }
+}
+
+impl State for Targeting {
+    fn render(&self, screen: &mut Screen) {
+        // the game is drawn under, only the path and the cursor go over it
+        for &(x, y) in map::line(self.origin, self.cursor).iter() {
+            screen.set_background(x, y, COLOR_PATH);
+        }
+        let (x, y) = self.cursor;
+        let color = if self.is_valid(self.cursor) { COLOR_VALID } else { COLOR_INVALID };
+        screen.set_background(x, y, color);
+
+        let width = screen.width();
+        screen.fill(0, 0, width, 1, tcod::colors::BLACK);
+        let name = self
+            .monsters
+            .iter()
+            .find(|&&(mx, my, _)| (mx, my) == self.cursor)
+            .map_or(String::new(), |&(_, _, ref name)| format!(" - {}", name));
+        screen.print(
+            0,
+            0,
+            &format!("Pick a target with the arrows, Tab or the mouse, Enter to confirm{}", name),
+            tcod::colors::LIGHT_CYAN,
+        );
+    }
+
+    fn layer(&self) -> Layer {
+        Layer::Transparent
+    }
+
+    fn update(&mut self) -> Transition {
+        Transition::None
+    }
+
+    fn handle_event(&mut self, event: Event) -> Transition {
+        use tcod::input::KeyCode;
+
+        match event {
+            Event::Key(key) => {
+                let (dx, dy) = match key.code {
+                    KeyCode::Up => (0, -1),
+                    KeyCode::Down => (0, 1),
+                    KeyCode::Left => (-1, 0),
+                    KeyCode::Right => (1, 0),
+                    KeyCode::Tab => {
+                        self.cycle();
+                        (0, 0)
+                    }
+                    KeyCode::Enter | KeyCode::NumPadEnter => return self.confirm(),
+                    KeyCode::Escape => return Transition::Pop,
+                    _ => (0, 0),
+                };
+                self.cursor = (self.cursor.0 + dx, self.cursor.1 + dy);
+                Transition::None
+            }
+            Event::MouseMove(x, y) => {
+                self.cursor = (x, y);
+                Transition::None
+            }
+            Event::MouseClick(x, y, MouseButton::Left) => {
+                self.cursor = (x, y);
+                self.confirm()
+            }
+            Event::MouseClick(_, _, MouseButton::Right) => Transition::Pop,
+        }
+    }
+}
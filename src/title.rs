@@ -0,0 +1,375 @@
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+
+use rand;
+use tcod;
+
+use game;
+use keymap;
+use menu;
+use monsters;
+use replay;
+use save;
+use screen::Screen;
+use state_machine::{Event, Layer, MouseButton, State, Transition};
+use vaults;
+
+const MENU_WIDTH: i32 = 30;
+const SEED_PROMPT_WIDTH: i32 = 30;
+/// Longest seed that can be typed, `u32::MAX` has 10 digits
+const MAX_SEED_DIGITS: usize = 10;
+const FRAME_RATES: [i32; 3] = [20, 30, 60];
+
+/// The game being recorded, if any, so every event it gets can be written down
+pub type Recording = Rc<RefCell<Option<Arc<Mutex<replay::Recorder>>>>>;
+
+/// Writes the recorded game to the replay file and stops recording
+pub fn stop_recording(recording: &Recording) {
+    if let Some(recorder) = recording.borrow_mut().take() {
+        if let Err(e) = recorder.lock().unwrap().save(replay::REPLAY_FILE) {
+            println!("could not save the replay: {}", e);
+        }
+    }
+}
+
+/// What can be changed from the options screen
+#[derive(Clone, Copy)]
+pub struct Settings {
+    /// new games are recorded to the replay file
+    pub record_replays: bool,
+    pub frame_rate: i32,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Entry {
+    NewGame,
+    NewGameFromSeed,
+    Continue,
+    Options,
+    Quit,
+}
+
+impl Entry {
+    fn label(&self) -> &'static str {
+        match *self {
+            Entry::NewGame => "New game",
+            Entry::NewGameFromSeed => "New game from a seed",
+            Entry::Continue => "Continue",
+            Entry::Options => "Options",
+            Entry::Quit => "Quit",
+        }
+    }
+}
+
+/// First screen of the program: every game is pushed from here and pops back to it
+pub struct TitleMenu {
+    monsters: monsters::MonsterBook,
+    vaults: vaults::VaultBook,
+    keymap: keymap::KeyMap,
+    width: i32,
+    height: i32,
+    /// seed given on the command line, used by the first new game
+    seed: Option<u32>,
+    recording: Recording,
+    settings: Rc<Cell<Settings>>,
+    /// where the seed prompt writes the typed seed
+    typed_seed: Rc<Cell<Option<u32>>>,
+    /// game to start on the next update, menus can only push states from there
+    pending_seed: Option<u32>,
+    /// set by a game whose player asked for a new one after dying
+    restart: Rc<Cell<bool>>,
+    entries: Vec<Entry>,
+    /// why the last action failed, like a save that could not be read
+    error: Option<String>,
+    /// problems found at startup that did not keep the game from running, like conflicting keys
+    warnings: Vec<String>,
+    /// the labels of the entries
+    options: menu::OptionList,
+}
+
+impl TitleMenu {
+    pub fn new(
+        monsters: monsters::MonsterBook,
+        vaults: vaults::VaultBook,
+        keymap: keymap::KeyMap,
+        width: i32,
+        height: i32,
+        seed: Option<u32>,
+        recording: Recording,
+        settings: Settings,
+    ) -> TitleMenu {
+        let mut menu = TitleMenu {
+            monsters: monsters,
+            vaults: vaults,
+            keymap: keymap,
+            width: width,
+            height: height,
+            seed: seed,
+            recording: recording,
+            settings: Rc::new(Cell::new(settings)),
+            typed_seed: Rc::new(Cell::new(None)),
+            pending_seed: None,
+            restart: Rc::new(Cell::new(false)),
+            entries: Vec::new(),
+            error: None,
+            warnings: Vec::new(),
+            options: menu::OptionList::new(Vec::new(), MENU_WIDTH),
+        };
+        menu.refresh_entries();
+        menu
+    }
+
+    /// Shows a line at the bottom of the menu for as long as it is up
+    pub fn warn(&mut self, warning: String) {
+        self.warnings.push(warning);
+    }
+
+    /// Continue is only offered when there is a save to continue from
+    fn refresh_entries(&mut self) {
+        self.entries = vec![Entry::NewGame, Entry::NewGameFromSeed];
+        if save::exists(save::SAVE_FILE) {
+            self.entries.push(Entry::Continue);
+        }
+        self.entries.push(Entry::Options);
+        self.entries.push(Entry::Quit);
+        let labels = self.entries.iter().map(|entry| entry.label().to_string()).collect();
+        self.options = menu::OptionList::new(labels, MENU_WIDTH);
+    }
+
+    fn new_game(&mut self, seed: u32) -> Transition {
+        let mut game = game::Game::new(seed, self.monsters.clone(), self.vaults.clone(), self.keymap.clone(), self.width, self.height);
+        game.set_restart_request(self.restart.clone());
+        // only a game started from its seed can be replayed
+        if self.settings.get().record_replays {
+            let recorder = Arc::new(Mutex::new(replay::Recorder::new(seed, &self.keymap)));
+            game.register_listener(recorder.clone());
+            *self.recording.borrow_mut() = Some(recorder);
+        }
+        Transition::Push(Box::new(game))
+    }
+
+    fn pick(&mut self, index: usize) -> Transition {
+        self.error = None;
+        match self.entries[index] {
+            Entry::NewGame => {
+                let seed = self.seed.take().unwrap_or_else(rand::random);
+                self.new_game(seed)
+            }
+            Entry::NewGameFromSeed => Transition::Push(Box::new(SeedPrompt::new(self.typed_seed.clone()))),
+            Entry::Continue => {
+                let game = game::Game::load(
+                    save::SAVE_FILE,
+                    self.monsters.clone(),
+                    self.vaults.clone(),
+                    self.keymap.clone(),
+                    self.width,
+                    self.height,
+                );
+                match game {
+                    Ok(mut game) => {
+                        game.set_restart_request(self.restart.clone());
+                        Transition::Push(Box::new(game))
+                    }
+                    Err(e) => {
+                        self.error = Some(format!("Could not load {}: {}", save::SAVE_FILE, e));
+                        Transition::None
+                    }
+                }
+            }
+            Entry::Options => Transition::Push(Box::new(OptionsMenu::new(self.settings.clone()))),
+            Entry::Quit => Transition::Quit,
+        }
+    }
+}
+
+impl State for TitleMenu {
+    fn render(&self, screen: &mut Screen) {
+        let (width, height) = (screen.width(), screen.height());
+        screen.fill(0, 0, width, height, tcod::colors::BLACK);
+
+        let title = "TOMBS OF THE ANCIENT KINGS";
+        screen.print(width / 2 - title.len() as i32 / 2, height / 4, title, tcod::colors::LIGHT_YELLOW);
+
+        let x = width / 2 - MENU_WIDTH / 2;
+        let y = height / 2 - self.options.height() / 2;
+        self.options.render(screen, x, y);
+        // warnings stack up over the line of the error
+        let top = height - 2 - self.warnings.len() as i32;
+        for (i, warning) in self.warnings.iter().enumerate() {
+            screen.print(1, top + i as i32, warning, tcod::colors::LIGHT_YELLOW);
+        }
+        if let Some(ref error) = self.error {
+            screen.print(1, height - 2, error, tcod::colors::LIGHT_RED);
+        }
+    }
+
+    fn update(&mut self) -> Transition {
+        match self.pending_seed.take() {
+            Some(seed) => self.new_game(seed),
+            None => Transition::None,
+        }
+    }
+
+    fn handle_event(&mut self, event: Event) -> Transition {
+        if let Some(index) = self.options.picked(&event) {
+            return self.pick(index);
+        }
+        match event {
+            Event::Key(key) if key.code == tcod::input::KeyCode::Escape => Transition::Quit,
+            _ => Transition::None,
+        }
+    }
+
+    fn on_resume(&mut self) {
+        // back from a game, which may have been saved or ended for good.
+        // A new game asked for from there gets a recording of its own.
+        stop_recording(&self.recording);
+        if let Some(seed) = self.typed_seed.take() {
+            self.pending_seed = Some(seed);
+        }
+        if self.restart.replace(false) {
+            self.pending_seed = Some(rand::random());
+        }
+        self.refresh_entries();
+    }
+}
+
+/// Asks for the seed of a new game, written to the shared cell on enter
+struct SeedPrompt {
+    digits: String,
+    seed: Rc<Cell<Option<u32>>>,
+    error: bool,
+}
+
+impl SeedPrompt {
+    fn new(seed: Rc<Cell<Option<u32>>>) -> SeedPrompt {
+        seed.set(None);
+        SeedPrompt {
+            digits: String::new(),
+            seed: seed,
+            error: false,
+        }
+    }
+}
+
+impl State for SeedPrompt {
+    fn render(&self, screen: &mut Screen) {
+        let x = screen.width() / 2 - SEED_PROMPT_WIDTH / 2;
+        let y = screen.height() / 2 - 2;
+        screen.fill(x, y, SEED_PROMPT_WIDTH, 4, tcod::colors::BLACK);
+        screen.print(x, y, "Seed of the new game:", tcod::colors::WHITE);
+        screen.print(x, y + 1, &format!("{}_", self.digits), tcod::colors::LIGHT_YELLOW);
+        if self.error {
+            screen.print(x, y + 3, "Not a valid seed", tcod::colors::LIGHT_RED);
+        }
+    }
+
+    fn layer(&self) -> Layer {
+        Layer::Transparent
+    }
+
+    fn update(&mut self) -> Transition {
+        Transition::None
+    }
+
+    fn handle_event(&mut self, event: Event) -> Transition {
+        use tcod::input::KeyCode;
+        let key = match event {
+            Event::Key(key) => key,
+            Event::MouseClick(_, _, MouseButton::Right) => return Transition::Pop,
+            _ => return Transition::None,
+        };
+        match key.code {
+            KeyCode::Escape => Transition::Pop,
+            KeyCode::Enter | KeyCode::NumPadEnter => match self.digits.parse() {
+                Ok(seed) => {
+                    self.seed.set(Some(seed));
+                    Transition::Pop
+                }
+                Err(_) => {
+                    self.error = true;
+                    Transition::None
+                }
+            },
+            KeyCode::Backspace => {
+                self.digits.pop();
+                self.error = false;
+                Transition::None
+            }
+            _ => {
+                if key.printable.is_ascii_digit() && self.digits.len() < MAX_SEED_DIGITS {
+                    self.digits.push(key.printable);
+                    self.error = false;
+                }
+                Transition::None
+            }
+        }
+    }
+}
+
+/// Toggles the settings, they apply right away
+struct OptionsMenu {
+    settings: Rc<Cell<Settings>>,
+}
+
+impl OptionsMenu {
+    fn new(settings: Rc<Cell<Settings>>) -> OptionsMenu {
+        OptionsMenu { settings: settings }
+    }
+
+    fn lines(&self) -> Vec<String> {
+        let settings = self.settings.get();
+        vec![
+            format!("(a) Record replays: {}", if settings.record_replays { "on" } else { "off" }),
+            format!("(b) Frame rate: {}", settings.frame_rate),
+        ]
+    }
+}
+
+impl State for OptionsMenu {
+    fn render(&self, screen: &mut Screen) {
+        let lines = self.lines();
+        let height = lines.len() as i32 + 2;
+        let x = screen.width() / 2 - MENU_WIDTH / 2;
+        let y = screen.height() / 2 - height / 2;
+        screen.fill(x, y, MENU_WIDTH, height, tcod::colors::BLACK);
+        screen.print(x, y, "Options", tcod::colors::LIGHT_YELLOW);
+        for (i, line) in lines.iter().enumerate() {
+            screen.print(x, y + 2 + i as i32, line, tcod::colors::WHITE);
+        }
+    }
+
+    fn layer(&self) -> Layer {
+        Layer::Transparent
+    }
+
+    fn update(&mut self) -> Transition {
+        Transition::None
+    }
+
+    fn handle_event(&mut self, event: Event) -> Transition {
+        let key = match event {
+            Event::Key(key) => key,
+            Event::MouseClick(_, _, MouseButton::Right) => return Transition::Pop,
+            _ => return Transition::None,
+        };
+        let mut settings = self.settings.get();
+        match key.printable {
+            'a' => settings.record_replays = !settings.record_replays,
+            'b' => {
+                // cycle through the frame rates
+                let next = FRAME_RATES
+                    .iter()
+                    .position(|&rate| rate == settings.frame_rate)
+                    .map_or(0, |i| (i + 1) % FRAME_RATES.len());
+                settings.frame_rate = FRAME_RATES[next];
+                tcod::system::set_fps(settings.frame_rate);
+            }
+            _ if key.code == tcod::input::KeyCode::Escape => return Transition::Pop,
+            _ => {}
+        }
+        self.settings.set(settings);
+        Transition::None
+    }
+}
@@ -0,0 +1,513 @@
+use std;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::Read;
+
+use tcod::random::Rng;
+
+use map::{Map, TileKind};
+use mapgen::{Layout, MapGenerator, SpawnRegion};
+
+pub const VAULTS_FILE: &str = "data/vaults.txt";
+
+/// Attempts at finding room for a vault before giving up on it
+const PLACEMENT_TRIES: i32 = 50;
+const MAX_VAULTS_PER_LEVEL: i32 = 2;
+
+/// One cell of a vault template
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum VaultCell {
+    /// leaves the generated map as it is
+    Outside,
+    Wall,
+    Floor,
+    /// an entrance, connected to the rest of the level
+    Door,
+    LockedDoor,
+    /// a hidden trap
+    Trap,
+    Rubble,
+    Monster,
+    Item,
+    /// where the player arrives on the level
+    Start,
+}
+
+impl VaultCell {
+    fn from_char(c: char) -> Option<VaultCell> {
+        match c {
+            ' ' => Some(VaultCell::Outside),
+            '#' => Some(VaultCell::Wall),
+            '.' => Some(VaultCell::Floor),
+            '+' => Some(VaultCell::Door),
+            '*' => Some(VaultCell::LockedDoor),
+            '^' => Some(VaultCell::Trap),
+            ':' => Some(VaultCell::Rubble),
+            'm' => Some(VaultCell::Monster),
+            'i' => Some(VaultCell::Item),
+            '@' => Some(VaultCell::Start),
+            _ => None,
+        }
+    }
+
+    fn is_open(&self) -> bool {
+        match *self {
+            VaultCell::Outside | VaultCell::Wall => false,
+            _ => true,
+        }
+    }
+
+    fn is_door(&self) -> bool {
+        *self == VaultCell::Door || *self == VaultCell::LockedDoor
+    }
+
+    /// The tile the cell becomes once the vault is dug, if it is not plain floor
+    fn tile(&self) -> Option<TileKind> {
+        match *self {
+            VaultCell::Door => Some(TileKind::Door { open: false }),
+            VaultCell::LockedDoor => Some(TileKind::LockedDoor),
+            VaultCell::Trap => Some(TileKind::Trap { revealed: false }),
+            VaultCell::Rubble => Some(TileKind::Rubble),
+            _ => None,
+        }
+    }
+}
+
+/// A hand-made room, stamped as is into the generated levels
+#[derive(Clone, Debug)]
+pub struct Vault {
+    pub name: String,
+    width: i32,
+    height: i32,
+    cells: Vec<VaultCell>,
+}
+
+impl Vault {
+    fn cell(&self, x: i32, y: i32) -> VaultCell {
+        self.cells[(x + y * self.width) as usize]
+    }
+
+    /// The same vault turned a quarter clockwise
+    fn rotated(&self) -> Vault {
+        let mut cells = Vec::with_capacity(self.cells.len());
+        for y in 0..self.width {
+            for x in 0..self.height {
+                cells.push(self.cell(y, self.height - 1 - x));
+            }
+        }
+        Vault {
+            name: self.name.clone(),
+            width: self.height,
+            height: self.width,
+            cells: cells,
+        }
+    }
+
+    /// The same vault seen in a mirror, left becomes right
+    fn mirrored(&self) -> Vault {
+        let mut cells = Vec::with_capacity(self.cells.len());
+        for y in 0..self.height {
+            for x in 0..self.width {
+                cells.push(self.cell(self.width - 1 - x, y));
+            }
+        }
+        Vault {
+            name: self.name.clone(),
+            width: self.width,
+            height: self.height,
+            cells: cells,
+        }
+    }
+
+    /// One of the 8 orientations of the vault, at random
+    fn random_orientation(&self, rng: &mut Rng) -> Vault {
+        let mut vault = self.clone();
+        for _ in 0..rng.get_int(0, 3) {
+            vault = vault.rotated();
+        }
+        if rng.get_int(0, 1) == 1 {
+            vault = vault.mirrored();
+        }
+        vault
+    }
+}
+
+/// A vault file that could not be read or that holds an invalid vault
+#[derive(Debug)]
+pub struct VaultError {
+    pub file: String,
+    /// line of the faulty vault, starting at 1
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl std::fmt::Display for VaultError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{}:{}: {}", self.file, line, self.message),
+            None => write!(f, "{}: {}", self.file, self.message),
+        }
+    }
+}
+
+/// Every vault template.
+///
+/// The file lists vaults one after the other, each starting with a `vault <name>` line followed by
+/// its rows. Lines starting with `;` are comments. See `VaultCell::from_char` for the characters.
+#[derive(Clone)]
+pub struct VaultBook {
+    vaults: Vec<Vault>,
+}
+
+impl VaultBook {
+    pub fn load(path: &str) -> Result<VaultBook, VaultError> {
+        let mut text = String::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut text))
+            .map_err(|e| VaultError {
+                file: path.into(),
+                line: None,
+                message: e.to_string(),
+            })?;
+        VaultBook::parse(path, &text)
+    }
+
+    /// Reads the vaults out of the text of a vault file, `path` only names the file in errors
+    fn parse(path: &str, text: &str) -> Result<VaultBook, VaultError> {
+        let error = |line: Option<usize>, message: String| VaultError {
+            file: path.into(),
+            line: line,
+            message: message,
+        };
+
+        // (name, line of the header, rows)
+        let mut sections: Vec<(String, usize, Vec<String>)> = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            if line.starts_with(';') {
+                continue;
+            }
+            if line.starts_with("vault") {
+                let name = line["vault".len()..].trim();
+                if name.is_empty() {
+                    return Err(error(Some(i + 1), "the vault has no name".into()));
+                }
+                sections.push((name.into(), i + 1, Vec::new()));
+            } else if let Some(section) = sections.last_mut() {
+                section.2.push(line.trim_end().into());
+            } else if !line.trim().is_empty() {
+                return Err(error(Some(i + 1), "rows found before any `vault` line".into()));
+            }
+        }
+
+        let mut vaults = Vec::new();
+        for (name, line, mut rows) in sections {
+            while rows.last().map_or(false, |row| row.is_empty()) {
+                rows.pop();
+            }
+            if rows.is_empty() {
+                return Err(error(Some(line), format!("vault `{}` is empty", name)));
+            }
+            let width = rows.iter().map(|row| row.chars().count()).max().unwrap_or(0) as i32;
+            let mut cells = Vec::new();
+            for (y, row) in rows.iter().enumerate() {
+                let mut chars: Vec<char> = row.chars().collect();
+                chars.resize(width as usize, ' ');
+                for c in chars {
+                    match VaultCell::from_char(c) {
+                        Some(cell) => cells.push(cell),
+                        None => {
+                            return Err(error(Some(line + y + 1), format!("unknown character `{}` in vault `{}`", c, name)))
+                        }
+                    }
+                }
+            }
+            if !cells.iter().any(|cell| cell.is_door()) {
+                return Err(error(Some(line), format!("vault `{}` has no door", name)));
+            }
+            vaults.push(Vault {
+                name: name,
+                width: width,
+                height: rows.len() as i32,
+                cells: cells,
+            });
+        }
+
+        Ok(VaultBook { vaults: vaults })
+    }
+
+    pub fn vaults(&self) -> &[Vault] {
+        &self.vaults
+    }
+}
+
+/// Lets another generator lay out the level, then stamps vaults into the solid rock left over and
+/// digs from their doors to the nearest open tile
+pub struct WithVaults {
+    inner: Box<MapGenerator>,
+    vaults: Vec<Vault>,
+}
+
+impl WithVaults {
+    pub fn new(inner: Box<MapGenerator>, vaults: Vec<Vault>) -> WithVaults {
+        WithVaults {
+            inner: inner,
+            vaults: vaults,
+        }
+    }
+
+    /// Whether the vault fits at (x, y) with a border of rock all around it. The border stays off the
+    /// outer edge of the map, so the doors never dig into it.
+    fn fits(map: &Map, vault: &Vault, x: i32, y: i32) -> bool {
+        if x < 2 || y < 2 || x + vault.width >= map.width() - 1 || y + vault.height >= map.height() - 1 {
+            return false;
+        }
+        for ty in (y - 1)..(y + vault.height + 1) {
+            for tx in (x - 1)..(x + vault.width + 1) {
+                if map.tile_at(tx, ty) != TileKind::Wall {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    /// Digs the shortest way from (x, y) to anything but rock without going through the vault
+    fn dig_to_open(map: &mut Map, vault: &Vault, vault_x: i32, vault_y: i32, from: (i32, i32)) {
+        let (width, height) = (map.width(), map.height());
+        let in_vault = |x: i32, y: i32| {
+            x >= vault_x && y >= vault_y && x < vault_x + vault.width && y < vault_y + vault.height
+        };
+        let mut previous: Vec<Option<(i32, i32)>> = vec![None; (width * height) as usize];
+        let mut queue = VecDeque::new();
+        previous[(from.0 + from.1 * width) as usize] = Some(from);
+        queue.push_back(from);
+        while let Some((x, y)) = queue.pop_front() {
+            if map.tile_at(x, y) != TileKind::Wall {
+                // walk back to the door, digging on the way
+                let mut tile = (x, y);
+                while tile != from {
+                    map.dig(tile.0, tile.1);
+                    tile = previous[(tile.0 + tile.1 * width) as usize].unwrap();
+                }
+                map.dig(from.0, from.1);
+                return;
+            }
+            for &(nx, ny) in [(x + 1, y), (x - 1, y), (x, y + 1), (x, y - 1)].iter() {
+                // the outer edge of the map stays solid
+                if nx < 1 || ny < 1 || nx >= width - 1 || ny >= height - 1 || in_vault(nx, ny) {
+                    continue;
+                }
+                let n = (nx + ny * width) as usize;
+                if previous[n].is_none() {
+                    previous[n] = Some((x, y));
+                    queue.push_back((nx, ny));
+                }
+            }
+        }
+    }
+
+    /// Stamps the vault with its top left corner at (x, y), returns the player start if it has one
+    fn stamp(&self, map: &mut Map, vault: &Vault, x: i32, y: i32, create_objects: &mut FnMut(&SpawnRegion, &mut Rng), rng: &mut Rng) -> Option<(i32, i32)> {
+        let mut start = None;
+        let mut monsters = Vec::new();
+        let mut items = Vec::new();
+        for vy in 0..vault.height {
+            for vx in 0..vault.width {
+                let cell = vault.cell(vx, vy);
+                let (tx, ty) = (x + vx, y + vy);
+                if cell.is_open() {
+                    map.dig(tx, ty);
+                }
+                match cell {
+                    VaultCell::Monster => monsters.push((tx, ty)),
+                    VaultCell::Item => items.push((tx, ty)),
+                    VaultCell::Start => start = Some((tx, ty)),
+                    _ => {}
+                }
+            }
+        }
+
+        // doors on the edge of the vault lead out of it
+        for vy in 0..vault.height {
+            for vx in 0..vault.width {
+                if !vault.cell(vx, vy).is_door() {
+                    continue;
+                }
+                let outside = if vx == 0 {
+                    Some((x - 1, y + vy))
+                } else if vx == vault.width - 1 {
+                    Some((x + vault.width, y + vy))
+                } else if vy == 0 {
+                    Some((x + vx, y - 1))
+                } else if vy == vault.height - 1 {
+                    Some((x + vx, y + vault.height))
+                } else {
+                    None
+                };
+                if let Some(outside) = outside {
+                    WithVaults::dig_to_open(map, vault, x, y, outside);
+                }
+            }
+        }
+
+        // doors and the like go in last, so the digging only ever looks for floor
+        for vy in 0..vault.height {
+            for vx in 0..vault.width {
+                if let Some(kind) = vault.cell(vx, vy).tile() {
+                    map.set_tile(x + vx, y + vy, kind);
+                }
+            }
+        }
+
+        if !monsters.is_empty() || !items.is_empty() {
+            create_objects(&SpawnRegion::Spots { monsters: monsters, items: items }, rng);
+        }
+        start
+    }
+}
+
+impl MapGenerator for WithVaults {
+    fn generate(&mut self, map: &mut Map, rng: &mut Rng, create_objects: &mut FnMut(&SpawnRegion, &mut Rng)) -> Layout {
+        let mut layout = self.inner.generate(map, rng, create_objects);
+        if self.vaults.is_empty() {
+            return layout;
+        }
+
+        for _ in 0..rng.get_int(0, MAX_VAULTS_PER_LEVEL) {
+            let index = rng.get_int(0, self.vaults.len() as i32 - 1) as usize;
+            let vault = self.vaults[index].random_orientation(rng);
+            for _ in 0..PLACEMENT_TRIES {
+                let x = rng.get_int(2, map.width() - 2);
+                let y = rng.get_int(2, map.height() - 2);
+                if WithVaults::fits(map, &vault, x, y) {
+                    if let Some(start) = self.stamp(map, &vault, x, y, create_objects, rng) {
+                        layout.start = start;
+                    }
+                    break;
+                }
+            }
+        }
+        layout
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pathfinding::Pathfinder;
+    use tcod::random::Algo;
+
+    /// Door on the top edge, every marker somewhere else
+    const LOPSIDED: &str = "vault lopsided\n#+#\nm.#\n#@i\n";
+
+    fn lopsided() -> Vault {
+        VaultBook::parse("test.txt", LOPSIDED).unwrap().vaults()[0].clone()
+    }
+
+    fn rows(vault: &Vault) -> Vec<String> {
+        (0..vault.height)
+            .map(|y| {
+                (0..vault.width)
+                    .map(|x| " #.+*^:mi@".chars().find(|&c| VaultCell::from_char(c) == Some(vault.cell(x, y))).unwrap())
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Leaves the map as it is
+    struct Untouched;
+
+    impl MapGenerator for Untouched {
+        fn generate(&mut self, _map: &mut Map, _rng: &mut Rng, _create_objects: &mut FnMut(&SpawnRegion, &mut Rng)) -> Layout {
+            Layout { start: (1, 1), exit: None }
+        }
+    }
+
+    #[test]
+    fn the_shipped_vaults_are_valid() {
+        assert!(!VaultBook::load(VAULTS_FILE).unwrap().vaults().is_empty());
+    }
+
+    #[test]
+    fn short_rows_are_padded_and_comments_skipped() {
+        let book = VaultBook::parse("test.txt", "; a comment\nvault nook\n#+#\n#.\n\nvault hall\n+..+\n").unwrap();
+        let names: Vec<&str> = book.vaults().iter().map(|vault| vault.name.as_str()).collect();
+        assert_eq!(names, vec!["nook", "hall"]);
+        assert_eq!(rows(&book.vaults()[0]), vec!["#+#", "#. "]);
+        assert_eq!(rows(&book.vaults()[1]), vec!["+..+"]);
+    }
+
+    #[test]
+    fn errors_name_the_file_and_the_line() {
+        let error = VaultBook::parse("test.txt", "vault bad\n#+#\n#x#\n").err().unwrap();
+        assert_eq!(error.to_string(), "test.txt:3: unknown character `x` in vault `bad`");
+        let error = VaultBook::parse("test.txt", "\nvault closed\n###\n").err().unwrap();
+        assert_eq!(error.to_string(), "test.txt:2: vault `closed` has no door");
+        let error = VaultBook::parse("test.txt", "#+#\n").err().unwrap();
+        assert_eq!(error.line, Some(1));
+    }
+
+    #[test]
+    fn rotation_turns_clockwise() {
+        let rotated = lopsided().rotated();
+        assert_eq!(rows(&rotated), vec!["#m#", "@.+", "i##"]);
+        let mut back = rotated;
+        for _ in 0..3 {
+            back = back.rotated();
+        }
+        assert_eq!(rows(&back), rows(&lopsided()));
+    }
+
+    #[test]
+    fn mirroring_swaps_left_and_right() {
+        let mirrored = lopsided().mirrored();
+        assert_eq!(rows(&mirrored), vec!["#+#", "#.m", "i@#"]);
+        assert_eq!(rows(&mirrored.mirrored()), rows(&lopsided()));
+    }
+
+    #[test]
+    fn vaults_keep_off_the_edge_of_the_map() {
+        let map = Map::from_rows(&["##########", "#..#######", "#..#######", "#..#######", "#..#######", "#..#######", "##########"]);
+        let vault = lopsided();
+        assert!(WithVaults::fits(&map, &vault, 5, 2));
+        // the border would reach the last column
+        assert!(!WithVaults::fits(&map, &vault, 6, 2));
+        assert!(!WithVaults::fits(&map, &vault, 5, 1));
+        // the border would cover floor
+        assert!(!WithVaults::fits(&map, &vault, 3, 2));
+    }
+
+    #[test]
+    fn stamping_places_the_markers_and_digs_out_of_the_door() {
+        let mut map = Map::from_rows(&["##########", "#..#######", "#..#######", "#..#######", "#..#######", "#..#######", "##########"]);
+        let vaults = WithVaults::new(Box::new(Untouched), Vec::new());
+        let vault = lopsided().rotated();
+        let mut rng = Rng::new_with_seed(Algo::CMWC, 1);
+        let mut spots = Vec::new();
+        let start = vaults.stamp(
+            &mut map,
+            &vault,
+            5,
+            2,
+            &mut |region: &SpawnRegion, _: &mut Rng| {
+                if let SpawnRegion::Spots { ref monsters, ref items } = *region {
+                    spots.push((monsters.clone(), items.clone()));
+                }
+            },
+            &mut rng,
+        );
+
+        assert_eq!(start, Some((5, 3)));
+        assert_eq!(spots, vec![(vec![(6, 2)], vec![(5, 4)])]);
+        assert_eq!(map.tile_at(7, 3), TileKind::Door { open: false });
+        assert_eq!(map.tile_at(6, 4), TileKind::Wall);
+        assert!(map.can_walk(8, 3));
+        assert!(Pathfinder::new(&map).astar((1, 1), (5, 3)).is_some());
+        for x in 0..map.width() {
+            assert!(map.tile_at(x, 0) == TileKind::Wall && map.tile_at(x, map.height() - 1) == TileKind::Wall);
+        }
+        for y in 0..map.height() {
+            assert!(map.tile_at(0, y) == TileKind::Wall && map.tile_at(map.width() - 1, y) == TileKind::Wall);
+        }
+    }
+}